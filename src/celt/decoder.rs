@@ -2,6 +2,7 @@ use std::mem;
 use std::ops::Range;
 
 use super::bitexact;
+use super::imdct15::IMDCT15;
 use crate::entropy::*;
 use crate::maths::*;
 use crate::packet::*;
@@ -14,11 +15,18 @@ const MAX_FRAME_SIZE: usize = SHORT_BLOCKSIZE * (1 << MAX_LOG_BLOCKS);
 const MAX_BANDS: usize = 21;
 const MIN_PERIOD: usize = 15;
 
+const DEEMPH_COEFF: f32 = 0.85000610;
+
 const SPREAD_NONE: usize = 0;
 const SPREAD_LIGHT: usize = 1;
 const SPREAD_NORMAL: usize = 2;
 const SPREAD_AGGRESSIVE: usize = 3;
 
+const LPC_ORDER: usize = 24;
+const MAX_PERIOD: usize = 1024;
+const PLC_PITCH_LAG_MIN: usize = 100;
+const PLC_MAX_LOSSES: usize = 5;
+
 #[derive(Debug, Default)]
 struct PostFilter {
     period: usize,
@@ -34,12 +42,123 @@ struct PostFilter {
 struct CeltFrame {
     pf: PostFilter,
     energy: [f32; MAX_BANDS],
-    prev_energy: [f32; MAX_BANDS],
+    prev_energy: [[f32; MAX_BANDS]; 2],
     collapse_masks: [u8; MAX_BANDS],
 
     buf: Vec<f32>, // TODO: replace with an array once const-generics
 
     deemph_coeff: f32,
+
+    lpc: [f32; LPC_ORDER],
+}
+
+impl PostFilter {
+    // Cross-fade from the old filter parameters to the current ones over the overlap
+    fn apply_transition(&self, buf: &mut [f32], off: usize) {
+        let t0 = self.period_old;
+        let t1 = self.period;
+
+        if self.gains[0] == 0.0 && self.gains_old[0] == 0.0 {
+            return;
+        }
+
+        let [g00, g01, g02] = self.gains_old;
+        let [g10, g11, g12] = self.gains;
+
+        let mut x1 = buf[off + 1 - t1];
+        let mut x2 = buf[off - t1];
+        let mut x3 = buf[off - t1 - 1];
+        let mut x4 = buf[off - t1 - 2];
+
+        for i in 0..OVERLAP {
+            let w = WINDOW[i] * WINDOW[i];
+            let x0 = buf[off + i + 2 - t1];
+            let k = off + i - t0;
+
+            buf[off + i] += (1.0 - w) * g00 * buf[k]
+                + (1.0 - w) * g01 * (buf[k - 1] + buf[k + 1])
+                + (1.0 - w) * g02 * (buf[k - 2] + buf[k + 2])
+                + w * g10 * x2
+                + w * g11 * (x1 + x3)
+                + w * g12 * (x0 + x4);
+
+            x4 = x3;
+            x3 = x2;
+            x2 = x1;
+            x1 = x0;
+        }
+    }
+
+    fn apply(&self, buf: &mut [f32], off: usize, len: usize) {
+        let t = self.period;
+
+        if self.gains[0] == 0.0 || len == 0 {
+            return;
+        }
+
+        let [g0, g1, g2] = self.gains;
+
+        let mut x4 = buf[off - t - 2];
+        let mut x3 = buf[off - t - 1];
+        let mut x2 = buf[off - t];
+        let mut x1 = buf[off + 1 - t];
+
+        for i in off..off + len {
+            let x0 = buf[i + 2 - t];
+
+            buf[i] += g0 * x2 + g1 * (x1 + x3) + g2 * (x0 + x4);
+
+            x4 = x3;
+            x3 = x2;
+            x2 = x1;
+            x1 = x0;
+        }
+    }
+
+    fn rotate(&mut self) {
+        self.period_old = self.period;
+        self.gains_old = self.gains;
+    }
+}
+
+impl CeltFrame {
+    // Apply the pitch post-filter to the frame just synthesized at 1024
+    // and shift the buffer so the output ends at 1024.
+    fn postfilter(&mut self, len: usize) {
+        let pf = &mut self.pf;
+
+        pf.apply_transition(&mut self.buf, 1024);
+
+        pf.rotate();
+        pf.period = pf.period_new;
+        pf.gains = pf.gains_new;
+
+        if len > OVERLAP {
+            pf.apply_transition(&mut self.buf, 1024 + OVERLAP);
+            pf.apply(&mut self.buf, 1024 + 2 * OVERLAP, len - 2 * OVERLAP);
+
+            pf.rotate();
+        }
+
+        self.buf.copy_within(len..len + 1024 + OVERLAP / 2, 0);
+    }
+
+    // Undo the encoder pre-emphasis and scale the output to the full scale
+    //
+    // It is not clamped here: in hybrid mode it is summed to the SILK output
+    // first, the decoder clamps the final output.
+    fn deemphasis(&mut self, out: &mut [f32]) {
+        let len = out.len();
+        let mut m = self.deemph_coeff;
+
+        for (o, &v) in out.iter_mut().zip(self.buf[1024 - len..1024].iter()) {
+            let tmp = v + m;
+            m = tmp * DEEMPH_COEFF;
+            *o = tmp / 32768.0;
+        }
+
+        self.deemph_coeff = m;
+    }
 }
 
 impl Default for CeltFrame {
@@ -47,12 +166,14 @@ impl Default for CeltFrame {
         CeltFrame {
             pf: Default::default(),
             energy: Default::default(),
-            prev_energy: Default::default(),
+            prev_energy: [[ENERGY_SILENCE; MAX_BANDS]; 2],
             collapse_masks: Default::default(),
 
             buf: vec![0f32; 2048],
 
             deemph_coeff: 0f32,
+
+            lpc: [0f32; LPC_ORDER],
         }
     }
 }
@@ -85,6 +206,11 @@ pub struct Celt {
 
     scratch: [f32; 22 * 8],
     seed: u32,
+
+    losses: usize,
+    skip_plc: bool,
+
+    imdct: Vec<IMDCT15>,
 }
 
 const POSTFILTER_TAPS: &[&[f32]] = &[
@@ -288,6 +414,34 @@ const LOG_FREQ_RANGE: &[u8] = &[
 
 const MAX_FINE_BITS: i32 = 8;
 
+const MEAN_ENERGY: &[f32] = &[
+    6.437500, 6.250000, 5.750000, 5.312500, 5.062500, 4.812500, 4.500000, 4.375000, 4.875000,
+    4.687500, 4.562500, 4.437500, 4.875000, 4.625000, 4.312500, 4.500000, 4.375000, 4.625000,
+    4.750000, 4.437500, 3.750000,
+];
+
+const ENERGY_SILENCE: f32 = -28.0;
+
+// sin(0.5 * PI * sin(0.5 * PI * (i + 0.5) / OVERLAP)^2)
+pub(crate) const WINDOW: &[f32] = &[
+    6.7286966e-05, 0.00060551348, 0.001681597, 0.0032947962, 0.0054439943, 0.008127692,
+    0.0113440014, 0.0150906327, 0.019364886, 0.024163635, 0.029483315, 0.035319905, 0.04166891,
+    0.04852535, 0.055883718, 0.063737999, 0.07208162, 0.08090743, 0.0902077, 0.09997411,
+    0.110197687, 0.12086883, 0.13197729, 0.14351214, 0.15546177, 0.167813888, 0.1805555, 0.1936729,
+    0.20715171, 0.22097682, 0.23513243, 0.24960208, 0.2643686, 0.27941419, 0.2947204, 0.3102682,
+    0.32603788, 0.3420093, 0.35816177, 0.37447407, 0.39092462, 0.40749142, 0.42415215, 0.44088423,
+    0.45766484, 0.47447104, 0.49127978, 0.50806798, 0.52481261, 0.5414908, 0.5580797, 0.574557,
+    0.5909005, 0.6070884, 0.6230995, 0.63891306, 0.65450896, 0.66986776, 0.6849708, 0.6998001,
+    0.7143387, 0.72857055, 0.74248043, 0.7560542, 0.76927895, 0.7821426, 0.7946343, 0.80674445,
+    0.8184646, 0.8297873, 0.8407067, 0.8512178, 0.861317, 0.87100183, 0.88027111, 0.8891248,
+    0.897564, 0.90559094, 0.913209, 0.9204227, 0.9272374, 0.93365955, 0.93969656, 0.9453567,
+    0.9506491, 0.9555835, 0.9601707, 0.9644217, 0.9683485, 0.97196334, 0.97527906, 0.97830883,
+    0.98106616, 0.9835648, 0.9858187, 0.9878419, 0.9896486, 0.9912527, 0.9926685, 0.9939097,
+    0.99499004, 0.995923, 0.9967216, 0.99739874, 0.99796667, 0.9984373, 0.998822, 0.9991315,
+    0.99937606, 0.99956527, 0.999708, 0.9998125, 0.99988613, 0.99993565, 0.999967, 0.99998518,
+    0.9999946, 0.99999859, 0.9999998, 1.0,
+];
+
 const BIT_INTERLEAVE: &[u8] = &[0, 1, 1, 1, 2, 3, 3, 3, 2, 3, 3, 3, 2, 3, 3, 3];
 
 const PVQ_U: &[u32] = &[
@@ -492,11 +646,6 @@ fn interleave_hadamard(
             }
         }
     }
-    println!("interleave");
-    for v in &buf[..size] {
-        println!("  {:#.10}", v);
-    }
-
     buf[..size].copy_from_slice(&scratch[..size]);
 }
 
@@ -509,11 +658,6 @@ fn deinterleave_hadamard(
 ) {
     let size = n0 * stride;
 
-    println!("before deinterleave");
-    for v in &buf[..size] {
-        println!("  {:#.10}", v);
-    }
-
     if hadamard {
         let shuffle = &HADAMARD_ORDERY[stride - 2..];
         for i in 0..stride {
@@ -529,11 +673,6 @@ fn deinterleave_hadamard(
         }
     }
 
-    println!("deinterleave");
-    for v in &scratch[..size] {
-        println!("  {:#.10}", v);
-    }
-
     buf[..size].copy_from_slice(&scratch[..size]);
 }
 
@@ -544,7 +683,6 @@ fn cwrsi(mut n: u32, mut k: u32, mut i: u32, y: &mut [i32]) -> u32 {
     let mut y = y.iter_mut();
 
     fn update(k0: u32, k: u32, s: i32, norm: &mut u32) -> i32 {
-        println!("{} - {}", k0, k);
         let d = k0 - k;
 
         let val = (d as i32 + s) ^ s;
@@ -554,11 +692,9 @@ fn cwrsi(mut n: u32, mut k: u32, mut i: u32, y: &mut [i32]) -> u32 {
 
     while n > 2 {
         let yy = y.next().unwrap();
-        println!("k {} n {} i {}", k, n, i);
         if k >= n {
             let row = pvq_u_row(n as usize);
             let p = row[k as usize + 1] as u32;
-            println!("pulse {}", p);
             let s = if i >= p {
                 i -= p;
                 -1
@@ -574,7 +710,6 @@ fn cwrsi(mut n: u32, mut k: u32, mut i: u32, y: &mut [i32]) -> u32 {
                 loop {
                     k -= 1;
                     p = pvq_u_row(k as usize)[n as usize];
-                    println!("pulse {}", p);
                     if i >= p {
                         break;
                     }
@@ -590,15 +725,12 @@ fn cwrsi(mut n: u32, mut k: u32, mut i: u32, y: &mut [i32]) -> u32 {
             }
 
             i -= p;
-            println!("-- i {} p {}", i, p);
             *yy = update(k0, k, s, &mut norm);
         } else {
             let mut p = pvq_u_row(k as usize)[n as usize] as u32;
             let q = pvq_u_row(k as usize + 1)[n as usize] as u32;
-            println!("i {i} p {} q {}", p, q);
             if i >= p && i < q {
                 i -= p;
-                println!("zeroing {i} {p}");
                 *yy = 0;
             } else {
                 let s = if i >= q {
@@ -618,7 +750,6 @@ fn cwrsi(mut n: u32, mut k: u32, mut i: u32, y: &mut [i32]) -> u32 {
                 }
 
                 i -= p;
-                println!("i {} p {}", i, p);
                 *yy = update(k0, k, s, &mut norm);
             }
         }
@@ -627,7 +758,6 @@ fn cwrsi(mut n: u32, mut k: u32, mut i: u32, y: &mut [i32]) -> u32 {
     {
         // n == 2
         let p = 2 * k + 1;
-        println!("p {} i {}", p, i);
         let s = if i >= p {
             i -= p;
             -1
@@ -642,7 +772,6 @@ fn cwrsi(mut n: u32, mut k: u32, mut i: u32, y: &mut [i32]) -> u32 {
         }
 
         let yy = y.next().unwrap();
-        println!("n == 2");
         *yy = update(k0, k, s, &mut norm);
     }
 
@@ -651,11 +780,9 @@ fn cwrsi(mut n: u32, mut k: u32, mut i: u32, y: &mut [i32]) -> u32 {
         let s = -(i as i32);
 
         let yy = y.next().unwrap();
-        println!("n == 1");
         *yy = update(k, 0, s, &mut norm);
     }
 
-    println!("norm {}", norm);
     norm
 }
 
@@ -668,7 +795,6 @@ fn decode_pulses(rd: &mut RangeDecoder, y: &mut [i32], n: usize, k: usize) -> f3
     }
 
     let idx = rd.decode_uniform(pvq_v(n, k));
-    println!("idx {}", idx);
     cwrsi(n as u32, k as u32, idx as u32, y) as f32
 }
 
@@ -683,11 +809,11 @@ fn exp_rotation1(x: &mut [f32], len: usize, stride: usize, c: f32, s: f32) {
         x[i] = c * x1 - s * x2;
     }
 
-    for i in (0..end - stride - 1).rev() {
+    for i in (0..end.saturating_sub(stride)).rev() {
         let x1 = x[i];
         let x2 = x[i + stride];
         x[i + stride] = c * x2 + s * x1;
-        x[0] = c * x1 - s * x2;
+        x[i] = c * x1 - s * x2;
     }
 }
 
@@ -711,7 +837,7 @@ fn exp_rotation(x: &mut [f32], len: usize, stride: usize, k: usize, spread: usiz
         }
     }
 
-    let _l = len / stride;
+    let len = len / stride;
     for i in 0..stride {
         if stride2 != 0 {
             exp_rotation1(&mut x[i * len..], len, stride2, s, c);
@@ -726,10 +852,10 @@ fn extract_collapse_mask(y: &[i32], b: usize) -> usize {
     }
 
     let mut collapse_mask = 0;
-    for block in y.chunks_exact(b) {
-        block.iter().enumerate().for_each(|(i, &v)| {
-            collapse_mask |= ((v != 0) as usize) << i;
-        });
+    for (i, block) in y.chunks_exact(y.len() / b).enumerate() {
+        if block.iter().any(|&v| v != 0) {
+            collapse_mask |= 1 << i;
+        }
     }
 
     return collapse_mask;
@@ -738,6 +864,7 @@ fn extract_collapse_mask(y: &[i32], b: usize) -> usize {
 fn bits2pulses(cache: &[u8], bits: i32) -> i32 {
     let mut low = 0;
     let mut high = cache[0] as usize;
+    let bits = bits - 1;
 
     for _i in 0..6 {
         let center = (low + high + 1) >> 1;
@@ -759,7 +886,7 @@ fn pulses2bits(cache: &[u8], pulses: i32) -> i32 {
     if pulses == 0 {
         0
     } else {
-        cache[pulses as usize] as i32
+        cache[pulses as usize] as i32 + 1
     }
 }
 
@@ -772,7 +899,7 @@ fn unquantize(
     blocks: usize,
     gain: f32,
 ) -> usize {
-    let mut y: [i32; 176] = unsafe { mem::uninitialized() };
+    let mut y = [0i32; 176];
 
     let gain = gain / decode_pulses(rd, &mut y, n, k).sqrt();
 
@@ -786,7 +913,7 @@ fn unquantize(
 }
 
 fn renormalize_vector(x: &mut [f32], gain: f32) {
-    let g: f32 = x.iter().map(|&v| v * v).sum();
+    let g: f32 = x.iter().fold(1e-15, |acc, &v| acc + v * v);
 
     let gain = gain / g.sqrt();
 
@@ -801,8 +928,6 @@ fn stereo_merge(x: &mut [f32], y: &mut [f32], mid: f32, n: usize) {
             (xp + xv * yv, side + yv * yv)
         });
 
-    println!("xp {} side {}", xp, side);
-
     let xp = xp * mid;
 
     let e = mid * mid + side;
@@ -811,7 +936,8 @@ fn stereo_merge(x: &mut [f32], y: &mut [f32], mid: f32, n: usize) {
     let e1 = e + 2f32 * xp;
 
     if e0 < 6e-4f32 || e1 < 6e-4f32 {
-        &mut y[..n].copy_from_slice(&x[..n]);
+        y[..n].copy_from_slice(&x[..n]);
+        return;
     }
 
     let gain0 = 1f32 / e0.sqrt();
@@ -826,6 +952,77 @@ fn stereo_merge(x: &mut [f32], y: &mut [f32], mid: f32, n: usize) {
     }
 }
 
+fn denormalize(coeff: &mut [f32], energy: &[f32], band: Range<usize>, lm: usize) {
+    for i in band {
+        let start = (FREQ_BANDS[i] as usize) << lm;
+        let end = (FREQ_BANDS[i + 1] as usize) << lm;
+        let norm = (energy[i] + MEAN_ENERGY[i]).min(32.0).exp2();
+
+        coeff[start..end].iter_mut().for_each(|v| *v *= norm);
+    }
+}
+
+// Overlap-add the tail of the previous block, stored in the first half
+// of the overlap, with the windowed head of the new one.
+fn window_overlap(buf: &mut [f32]) {
+    for i in 0..OVERLAP / 2 {
+        let j = OVERLAP - 1 - i;
+        let s0 = buf[i];
+        let s1 = buf[j];
+        let wi = WINDOW[i];
+        let wj = WINDOW[j];
+
+        buf[i] = s0 * wj - s1 * wi;
+        buf[j] = s0 * wi + s1 * wj;
+    }
+}
+
+// Autocorrelation of `x` windowed at both ends, up to `ac.len() - 1` lags
+fn autocorr(x: &[f32], ac: &mut [f32]) {
+    let n = x.len();
+    let mut xx = x.to_vec();
+
+    for i in 0..OVERLAP {
+        xx[i] *= WINDOW[i];
+        xx[n - i - 1] *= WINDOW[i];
+    }
+
+    for (k, ac) in ac.iter_mut().enumerate() {
+        *ac = xx[k..].iter().zip(xx.iter()).map(|(&a, &b)| a * b).sum();
+    }
+}
+
+// Levinson-Durbin recursion, `ac` has `lpc.len() + 1` lags
+fn levinson(ac: &[f32], lpc: &mut [f32]) {
+    let mut error = ac[0];
+
+    lpc.iter_mut().for_each(|v| *v = 0.0);
+
+    if ac[0] == 0.0 {
+        return;
+    }
+
+    for i in 0..lpc.len() {
+        let rr = (0..i).fold(ac[i + 1], |rr, j| rr + lpc[j] * ac[i - j]);
+        let r = -rr / error;
+
+        lpc[i] = r;
+        for j in 0..i.div_ceil(2) {
+            let tmp1 = lpc[j];
+            let tmp2 = lpc[i - 1 - j];
+            lpc[j] = tmp1 + r * tmp2;
+            lpc[i - 1 - j] = tmp2 + r * tmp1;
+        }
+
+        error -= r * r * error;
+
+        // Stop once the prediction gain reaches 30dB
+        if error < 0.001 * ac[0] {
+            break;
+        }
+    }
+}
+
 struct BandInfo {
     itheta: usize,
     inv: bool,
@@ -861,6 +1058,9 @@ impl Celt {
             remaining2: 0,
             scratch: unsafe { mem::zeroed() },
             seed: 0,
+            losses: 0,
+            skip_plc: true,
+            imdct: (0..=MAX_LOG_BLOCKS).map(|n| IMDCT15::new(n + 3)).collect(),
         }
     }
 
@@ -868,9 +1068,21 @@ impl Celt {
         self.stereo_pkt = pkt.stereo;
     }
 
-    fn reset_gains(&mut self) {
-        self.frames[0].pf.gains_new = [0.0; 3];
-        self.frames[1].pf.gains_new = [0.0; 3];
+    /// Reset the inter-frame state, as if no frame had been decoded
+    pub fn flush(&mut self) {
+        self.frames = Default::default();
+        self.seed = 0;
+        self.losses = 0;
+        self.skip_plc = true;
+    }
+
+    // No post-filter unless the frame signals one, the tapset is folded
+    // in the gains
+    fn reset_postfilter(&mut self) {
+        for frame in self.frames.iter_mut() {
+            frame.pf.period_new = 0;
+            frame.pf.gains_new = [0.0; 3];
+        }
     }
 
     fn parse_postfilter(&mut self, rd: &mut RangeDecoder) {
@@ -886,22 +1098,16 @@ impl Celt {
                 0
             };
 
-            println!(
-                "postfilter: octave {}, period {}, gain {}, tapset {}",
-                octave, period, gain, tapset
-            );
             let taps = POSTFILTER_TAPS[tapset];
             for frame in self.frames.iter_mut() {
                 frame.pf.period_new = period.max(MIN_PERIOD);
                 frame.pf.gains_new = [taps[0] * gain, taps[1] * gain, taps[2] * gain];
             }
-        } else {
-            println!("postfilter: no");
         }
     }
 
     fn decode_coarse_energy(&mut self, rd: &mut RangeDecoder, band: Range<usize>) {
-        let (alpha, beta, model) = if rd.available() > 3 && rd.decode_logp(3) {
+        let (alpha, beta, model) = if rd.available() >= 3 && rd.decode_logp(3) {
             (
                 0f32,
                 1f32 - 4915f32 / 32768f32,
@@ -915,8 +1121,6 @@ impl Celt {
             )
         };
 
-        println!("model {:.6} {:.6}", alpha, beta);
-
         let mut prev = [0f32; 2];
         let frames = &mut self.frames;
         for i in 0..MAX_BANDS {
@@ -927,24 +1131,18 @@ impl Celt {
                     *en = 0.0
                 } else {
                     let available = rd.available();
-                    println!("available {}", available);
                     let value = if available >= 15 {
                         let k = i.min(20) << 1;
-                        let v = rd
-                            .decode_laplace((model[k] as usize) << 7, (model[k + 1] as isize) << 6);
-                        println!("decode_laplace {:.6} <- {} {}", v, i, k);
-                        v
-                    } else if available >= 1 {
+                        rd.decode_laplace((model[k] as usize) << 7, (model[k + 1] as isize) << 6)
+                    } else if available >= 2 {
                         let v = rd.decode_icdf(MODEL_ENERGY_SMALL) as isize;
                         (v >> 1) ^ -(v & 1)
+                    } else if available >= 1 {
+                        -(rd.decode_logp(1) as isize)
                     } else {
                         -1
                     } as f32;
 
-                    println!(
-                        "energy {}/{} {:.6} * {:.6} + {:.6} + {:.6}",
-                        i, j, *en, alpha, prev[j], value
-                    );
                     *en = en.max(-9f32) * alpha + prev[j] + value;
                     prev[j] += beta * value;
                 }
@@ -968,9 +1166,7 @@ impl Celt {
                         let available = rd.available();
                         let value = if available >= 15 {
                             let k = i.min(20) << 1;
-                            let v = rd.decode_laplace((model[k] as usize) << 7, (model[k + 1] as isize) << 6)
-                            println!("decode_laplace {} <- {} {}", v, i, k);
-                            v
+                            rd.decode_laplace((model[k] as usize) << 7, (model[k + 1] as isize) << 6)
                         } else if available >= 1 {
                             let v = rd.decode_icdf(MODEL_ENERGY_SMALL) as isize;
                             (v >> 1) ^ - (v & 1)
@@ -987,8 +1183,6 @@ impl Celt {
                     });
                 });
         */
-        println!("{:#.6?}", &frames[0].energy[..]);
-        println!("{:#.6?}", &frames[1].energy[..]);
     }
 
     fn decode_tf_changes(&mut self, rd: &mut RangeDecoder, band: Range<usize>, transient: bool) {
@@ -999,15 +1193,13 @@ impl Celt {
         let tf_select = TF_SELECT[self.lm][transient as usize];
 
         let select_bit = self.lm != 0 && available > bits.0;
-        println!("select_bit {} {}", select_bit, available);
 
         let mut field_bits = bits.0;
         let mut diff = false;
         let mut changed = false;
-        for (i, tf_change) in tf_changed[band.clone()].iter_mut().enumerate() {
-            if available > field_bits + select_bit as usize {
+        for tf_change in tf_changed[band.clone()].iter_mut() {
+            if available >= field_bits + select_bit as usize {
                 diff ^= rd.decode_logp(field_bits);
-                println!("band {} bits {} {}", i, field_bits, diff);
                 available = rd.available();
                 changed |= diff;
             }
@@ -1029,18 +1221,17 @@ impl Celt {
                 *tf = tf_select[select as usize][changed as usize];
             }
         }
-        println!("tf_change {:#?}", &self.tf_change[band]);
     }
 
     fn decode_allocation(&mut self, rd: &mut RangeDecoder, band: Range<usize>) {
-        let mut caps: [i32; MAX_BANDS] = unsafe { mem::uninitialized() };
+        let mut caps = [0i32; MAX_BANDS];
         let mut threshold = [0; MAX_BANDS];
         let mut trim_offset = [0; MAX_BANDS];
         let mut boost = [0; MAX_BANDS];
         let scale = self.lm + self.stereo_pkt as usize;
         let mut skip_startband = band.start;
 
-        let _spread = if rd.available() > 4 {
+        self.spread = if rd.available() >= 4 {
             rd.decode_icdf(MODEL_SPREAD)
         } else {
             SPREAD_NORMAL
@@ -1054,24 +1245,22 @@ impl Celt {
                 *cap = (static_cap as i32 + 64) * (freq_range as i32) << scale >> 2;
             });
 
-        println!("caps {:#?}", &caps[..]);
-
         let mut dynalloc = 6;
         let mut boost_size = 0;
 
-        println!("consumed {}", rd.tell_frac());
-
         for i in band.clone() {
-            let quanta = FREQ_RANGE[i] << scale;
-            let quanta = (quanta << 3).min(quanta.max(6 << 3)) as i32;
+            let quanta = (FREQ_RANGE[i] as i32) << scale;
+            let quanta = (quanta << 3).min(quanta.max(6 << 3));
             let mut band_dynalloc = dynalloc;
-            while (band_dynalloc << 3) + boost_size < rd.available_frac() && boost[i] < caps[i] {
-                let add = rd.decode_logp(band_dynalloc);
+            while (band_dynalloc << 3) + boost_size < rd.available_frac() as i32
+                && boost[i] < caps[i]
+            {
+                let add = rd.decode_logp(band_dynalloc as usize);
                 if !add {
                     break;
                 }
                 boost[i] += quanta;
-                boost_size += quanta as usize;
+                boost_size += quanta;
                 band_dynalloc = 1;
             }
 
@@ -1080,24 +1269,21 @@ impl Celt {
             }
         }
 
-        let alloc_trim = if rd.available_frac() > boost_size + (6 << 3) {
+        let alloc_trim = if rd.available_frac() as i32 >= boost_size + (6 << 3) {
             rd.decode_icdf(ALLOC_TRIM)
         } else {
             5
         } as i32;
 
-        println!("alloc_trim {}", alloc_trim);
-
-        let mut available = rd.available_frac() - 1;
+        let mut available = (rd.len() * 8) as i32 - rd.tell_frac() as i32 - 1;
         self.anticollapse_bit =
-            if self.blocks > 1 && self.lm >= 2 && available >= (self.lm + 2) << 3 {
+            if self.blocks > 1 && self.lm >= 2 && available >= ((self.lm + 2) << 3) as i32 {
                 available -= 1 << 3;
                 1 << 3
             } else {
                 0
             };
-
-        println!("anticollapse_bit {}", self.anticollapse_bit);
+        available = available.max(0);
 
         let skip_bit = if available >= 1 << 3 {
             available -= 1 << 3;
@@ -1106,10 +1292,8 @@ impl Celt {
             0
         };
 
-        println!("skip_bit {}", skip_bit);
-
         let (mut intensity_stereo_bit, dual_stereo_bit) = if self.stereo_pkt {
-            let intensity_stereo = LOG2_FRAC[band.end - band.start] as usize;
+            let intensity_stereo = LOG2_FRAC[band.end - band.start] as i32;
             if intensity_stereo <= available {
                 available -= intensity_stereo;
                 let dual_stereo = if available >= 1 << 3 {
@@ -1126,28 +1310,40 @@ impl Celt {
             (0, 0)
         };
 
-        println!("intensity_stereo_bit {}", intensity_stereo_bit);
+        let coded_channel_bits = (self.stereo_pkt as i32 + 1) << 3;
 
         for i in band.clone() {
             let trim = alloc_trim - (5 + self.lm) as i32;
             let range = FREQ_RANGE[i] as i32 * (band.end - i - 1) as i32;
             let lm = self.lm + 3;
             let scale = lm as i32 + self.stereo_pkt as i32;
-            let stereo_threshold = (self.stereo_pkt as i32) << 8;
 
-            threshold[i] = ((3 * FREQ_RANGE[i] as i32) << lm >> 4).max(stereo_threshold);
+            threshold[i] = ((3 * FREQ_RANGE[i] as i32) << lm >> 4).max(coded_channel_bits);
 
             trim_offset[i] = trim * (range << scale) >> 6;
 
-            if FREQ_RANGE[i] << self.lm == 1 {
-                trim_offset[i] -= stereo_threshold;
+            if (FREQ_RANGE[i] as usize) << self.lm == 1 {
+                trim_offset[i] -= coded_channel_bits;
             }
-
-            println!("trim_offset {} {}", i, trim_offset[i]);
         }
 
         const CELT_VECTOR: usize = 11;
-        let coded_channel_bits = (self.stereo_pkt as i32 + 1) << 3;
+
+        let trim_bits = |bits: i32, i: usize| -> i32 {
+            if bits > 0 {
+                (bits + trim_offset[i]).max(0)
+            } else {
+                bits
+            }
+        };
+
+        let bits_estimation = |idx: usize, i: usize| -> i32 {
+            let bits = (FREQ_RANGE[i] as i32 * STATIC_ALLOC[idx][i] as i32)
+                << (self.stereo_pkt as i32)
+                << self.lm
+                >> 2;
+            trim_bits(bits, i)
+        };
 
         let mut low = 1;
         let mut high = CELT_VECTOR - 1;
@@ -1157,63 +1353,36 @@ impl Celt {
             let mut total = 0;
 
             for i in band.clone().rev() {
-                let bandbits = (FREQ_RANGE[i] as i32 * STATIC_ALLOC[center][i] as i32)
-                    << (self.stereo_pkt as i32)
-                    << self.lm
-                    >> 2;
-
-                println!("bandbits {}", bandbits);
-
-                let bandbits = if bandbits != 0 {
-                    (bandbits + trim_offset[i]).max(0)
-                } else {
-                    bandbits
-                } + boost[i];
+                let bandbits = bits_estimation(center, i) + boost[i];
 
                 if bandbits >= threshold[i] || done {
                     done = true;
                     total += bandbits.min(caps[i]);
-                } else {
-                    if bandbits >= coded_channel_bits {
-                        total += coded_channel_bits;
-                    }
+                } else if bandbits >= coded_channel_bits {
+                    total += coded_channel_bits;
                 }
-
-                println!("total {} {}", total, available);
             }
 
-            if total as usize > available {
+            if total > available {
                 high = center - 1;
             } else {
                 low = center + 1;
             }
-            println!("{} {} {}", high, low, center);
         }
 
-        println!("high {} low {}", high, low);
-
         high = low;
         low -= 1;
 
         let mut bits1 = [0; MAX_BANDS];
         let mut bits2 = [0; MAX_BANDS];
 
-        println!("high {} low {}", high, low);
-
         for i in band.clone() {
-            let bits_estimation = |idx: usize| -> i32 {
-                let bits = (FREQ_RANGE[i] as i32 * STATIC_ALLOC[idx][i] as i32)
-                    << (self.stereo_pkt as i32)
-                    << self.lm
-                    >> 2;
-                if bits != 0 {
-                    (bits + trim_offset[i]).max(0)
-                } else {
-                    bits
-                }
+            bits1[i] = bits_estimation(low, i);
+            bits2[i] = if high >= CELT_VECTOR {
+                trim_bits(caps[i], i)
+            } else {
+                bits_estimation(high, i)
             };
-            bits1[i] = bits_estimation(low);
-            bits2[i] = bits_estimation(high);
 
             if boost[i] != 0 {
                 if low != 0 {
@@ -1226,7 +1395,6 @@ impl Celt {
             }
 
             bits2[i] = (bits2[i] - bits1[i]).max(0);
-            println!("bits2 {}", bits2[i]);
         }
 
         const ALLOC_STEPS: usize = 6;
@@ -1250,7 +1418,7 @@ impl Celt {
                 }
             }
 
-            if total as usize > available {
+            if total > available {
                 high = center;
             } else {
                 low = center;
@@ -1276,8 +1444,6 @@ impl Celt {
             let bits = bits.min(caps[i]);
             self.pulses[i] = bits;
             total += bits;
-
-            println!("total {}", total);
         }
 
         let mut bands = band.clone().rev();
@@ -1286,7 +1452,6 @@ impl Celt {
             let j = bands.next().unwrap();
             let codedband = j + 1;
 
-            println!("codedband {} {}", codedband, j);
             if j == skip_startband {
                 available += skip_bit;
                 break codedband;
@@ -1294,14 +1459,15 @@ impl Celt {
 
             let band_delta = (FREQ_BANDS[codedband] - FREQ_BANDS[band.start]) as i32;
             let (bits, remaining) = {
-                let remaining = available as i32 - total;
+                let remaining = available - total;
                 let bits = remaining / band_delta;
                 (bits, remaining - bits * band_delta)
             };
-            let mut allocation =
-                self.pulses[j] + bits * FREQ_BANDS[j] as i32 + 0.max(remaining - band_delta);
+            let mut allocation = self.pulses[j]
+                + bits * FREQ_RANGE[j] as i32
+                + 0.max(remaining - (FREQ_BANDS[j] - FREQ_BANDS[band.start]) as i32);
 
-            if allocation >= threshold[j].max(coded_channel_bits) {
+            if allocation >= threshold[j].max(coded_channel_bits + (1 << 3)) {
                 if rd.decode_logp(1) {
                     break codedband;
                 }
@@ -1312,9 +1478,9 @@ impl Celt {
 
             total -= self.pulses[j];
             if intensity_stereo_bit != 0 {
-                total -= intensity_stereo_bit as i32;
-                intensity_stereo_bit = LOG2_FRAC[j - band.start] as usize;
-                total += intensity_stereo_bit as i32;
+                total -= intensity_stereo_bit;
+                intensity_stereo_bit = LOG2_FRAC[j - band.start] as i32;
+                total += intensity_stereo_bit;
             }
 
             self.pulses[j] = if allocation >= coded_channel_bits {
@@ -1324,8 +1490,6 @@ impl Celt {
             };
 
             total += self.pulses[j];
-
-            println!("band skip total {}", total);
         };
 
         self.intensity_stereo = if intensity_stereo_bit != 0 {
@@ -1343,19 +1507,14 @@ impl Celt {
             false
         };
 
-        println!(
-            "intensity {}, dual {}",
-            self.intensity_stereo, self.dual_stereo as usize
-        );
-
         let band_delta = (FREQ_BANDS[codedband] - FREQ_BANDS[band.start]) as i32;
         let (bandbits, mut remaining) = {
-            let remaining = available as i32 - total;
+            let remaining = available - total;
             let bits = remaining / band_delta;
             (bits, remaining - bits * band_delta)
         };
 
-        for i in band.clone() {
+        for i in band.start..codedband {
             let freq_range = FREQ_RANGE[i] as i32;
             let bits = remaining.min(freq_range);
 
@@ -1363,13 +1522,11 @@ impl Celt {
             remaining -= bits;
         }
 
-        println!("remaining {}", remaining);
-
         let mut extrabits = 0;
 
         const FINE_OFFSET: i32 = 21;
 
-        for i in band.clone() {
+        for i in band.start..codedband {
             let n = (FREQ_RANGE[i] as i32) << self.lm;
             let prev_extra = extrabits;
             self.pulses[i] += extrabits;
@@ -1385,8 +1542,6 @@ impl Celt {
                 let dof_channels = dof * (LOG_FREQ_RANGE[i] as i32 + duration);
                 let mut offset = (dof_channels >> 1) - dof * FINE_OFFSET;
 
-                println!("dof {} {} {}", dof, dof_channels, offset);
-
                 if n == 2 {
                     offset += dof << 1;
                 }
@@ -1401,17 +1556,15 @@ impl Celt {
                 let pulse = self.pulses[i] + offset;
 
                 let fine_bits = (pulse + (dof << 2)) / (dof << 3);
-                println!("pulses {}, offset {}", self.pulses[i], offset);
                 let max_bits = (self.pulses[i] >> 3) >> (self.stereo_pkt as usize);
                 let max_bits = max_bits.min(MAX_FINE_BITS).max(0);
 
                 self.fine_bits[i] = fine_bits.max(0).min(max_bits);
-                println!("fine_bits {} {}", fine_bits, self.fine_bits[i]);
                 self.fine_priority[i] = self.fine_bits[i] * (dof << 3) >= pulse;
 
                 self.pulses[i] -= self.fine_bits[i] << (self.stereo_pkt as usize) << 3;
             } else {
-                extrabits = (self.pulses[i] - ((self.stereo_pkt as i32 + 1) << 3)).max(0);
+                extrabits = (self.pulses[i] - coded_channel_bits).max(0);
                 self.pulses[i] -= extrabits;
                 self.fine_bits[i] = 0;
                 self.fine_priority[i] = true;
@@ -1428,9 +1581,6 @@ impl Celt {
 
                 extrabits -= extra_fine;
             }
-
-            println!("extrabits {}", extrabits);
-            println!("fine_bits {}", self.fine_bits[i]);
         }
 
         self.remaining = extrabits;
@@ -1439,8 +1589,6 @@ impl Celt {
             self.fine_bits[i] = self.pulses[i] >> (self.stereo_pkt as usize) >> 3;
             self.pulses[i] = 0;
             self.fine_priority[i] = self.fine_bits[i] < 1;
-
-            println!("fine_bits end {}", self.fine_bits[i]);
         }
 
         self.codedband = codedband;
@@ -1455,14 +1603,35 @@ impl Celt {
             for f in 0..self.stereo_pkt as usize + 1 {
                 let frame = &mut self.frames[f];
                 let q2 = rd.rawbits(self.fine_bits[i] as usize) as f32;
-                println!("-- fine_bits {}", self.fine_bits[i]);
                 let offset = (q2 + 0.5) * (1 << (14 - self.fine_bits[i])) as f32 / 16384.0 - 0.5;
-                println!("q2 {}", q2);
                 frame.energy[i] += offset;
             }
         }
     }
 
+    fn decode_final_energy(&mut self, rd: &mut RangeDecoder, band: Range<usize>) {
+        let channels = self.stereo_pkt as usize + 1;
+        let mut bits_left = rd.len() as isize - rd.tell() as isize;
+
+        for &priority in &[false, true] {
+            for i in band.clone() {
+                if bits_left < channels as isize {
+                    break;
+                }
+                if self.fine_priority[i] != priority || self.fine_bits[i] >= MAX_FINE_BITS {
+                    continue;
+                }
+
+                for frame in self.frames[..channels].iter_mut() {
+                    let q2 = rd.rawbits(1) as f32;
+                    let offset = (q2 - 0.5) * (1 << (14 - self.fine_bits[i] - 1)) as f32 / 16384.0;
+                    frame.energy[i] += offset;
+                    bits_left -= 1;
+                }
+            }
+        }
+    }
+
     fn decode_band_1(
         &mut self,
         rd: &mut RangeDecoder,
@@ -1498,19 +1667,18 @@ impl Celt {
         &self,
         rd: &mut RangeDecoder,
         band: usize,
-        lm: usize,
+        lm: isize,
         n: usize,
-        b: usize,
+        b: i32,
         b0: usize,
         blocks: usize,
         dualstereo: bool,
         fill: usize,
     ) -> BandInfo {
-        const QTHETA_OFFSET: usize = 4;
-        const QTHETA_OFFSET_TWOPHASE: usize = 16;
+        const QTHETA_OFFSET: i32 = 4;
+        const QTHETA_OFFSET_TWOPHASE: i32 = 16;
         const BITRES: i32 = 2 << 3;
-        println!("band {}", band);
-        let pulse_cap = LOG_FREQ_RANGE[band] as usize + lm * 8;
+        let pulse_cap = LOG_FREQ_RANGE[band] as i32 + lm as i32 * 8;
         let offset = (pulse_cap >> 1)
             - if dualstereo && n == 2 {
                 QTHETA_OFFSET_TWOPHASE
@@ -1525,8 +1693,7 @@ impl Celt {
                 2 * n - 2
             } else {
                 2 * n - 1
-            };
-            println!("n2 {} pulse_cap {} b {}", n2, pulse_cap, b);
+            } as i32;
             let qb = (b - pulse_cap - (4 << 3))
                 .min((b + n2 * offset) / n2)
                 .min(8 << 3);
@@ -1534,12 +1701,10 @@ impl Celt {
             if qb < (1 << 3 >> 1) {
                 1
             } else {
-                ((QN_EXP2[qb & 0x7] as usize >> (14 - (qb >> 3))) + 1) >> 1 << 1
+                ((QN_EXP2[(qb & 0x7) as usize] as usize >> (14 - (qb >> 3))) + 1) >> 1 << 1
             }
         };
 
-        println!("qn {}", qn);
-
         let tell_frac = rd.tell_frac();
         let (itheta, inv) = if qn != 1 {
             let itheta = if dualstereo && n > 2 {
@@ -1551,7 +1716,7 @@ impl Celt {
             };
             (itheta * 16384 / qn, false)
         } else {
-            let inv = if dualstereo && b as i32 > BITRES && self.remaining2 > BITRES {
+            let inv = if dualstereo && b > BITRES && self.remaining2 > BITRES {
                 rd.decode_logp(2)
             } else {
                 false
@@ -1593,7 +1758,10 @@ impl Celt {
     }
 
     fn rng(&mut self) -> u32 {
-        self.seed = 1664525 * self.seed + 1013904223;
+        self.seed = self
+            .seed
+            .wrapping_mul(1664525)
+            .wrapping_add(1013904223);
 
         self.seed
     }
@@ -1662,17 +1830,17 @@ impl Celt {
         cm
     }
 
-    fn decode_band<'a>(
+    fn decode_band(
         &mut self,
         rd: &mut RangeDecoder,
         band: usize,
-        mut mid_buf: &'a mut [f32],
-        mut side_buf: Option<&'a mut [f32]>,
+        x: &mut [f32],
+        y: Option<&mut [f32]>,
         mut n: usize,
         mut b: i32,
         mut blocks: usize,
-        mut lowband: Option<&'a [f32]>,
-        mut lowband_out: Option<&'a mut [f32]>,
+        lowband: Option<&[f32]>,
+        mut lowband_out: Option<&mut [f32]>,
         mut lm: isize,
         level: usize,
         gain: f32,
@@ -1680,25 +1848,15 @@ impl Celt {
     ) -> usize {
         let mut n_b = n / blocks;
         let mut n_b0 = n_b;
-        let dualstereo = side_buf.is_some();
+        let dualstereo = y.is_some();
         let n0 = n;
         let mut b0 = blocks;
 
         let mut time_divide = 0;
         let longblocks = b0 == 1;
-        println!(
-            "decode_band N={} lowband_out {}",
-            n,
-            lowband_out.is_some() as usize
-        );
-
-        println!("mid_buf");
-        for v in mid_buf[..n].iter() {
-            println!("{:.08}", v);
-        }
 
         if n == 1 {
-            self.decode_band_1(rd, mid_buf, side_buf, lowband_out);
+            self.decode_band_1(rd, x, y, lowband_out);
 
             return 1;
         }
@@ -1709,17 +1867,6 @@ impl Celt {
             let mut tf_change = self.tf_change[band];
             let recombine = if tf_change > 0 { tf_change } else { 0 };
 
-            println!("recombine {}", recombine);
-
-            if let Some(ref lowband) = lowband {
-                println!("lowband");
-                for v in lowband.iter() {
-                    println!("{:.08}", v);
-                }
-            }
-
-            if b0 > 1 || (recombine != 0 || ((n_b & 1) == 0 && tf_change < 0)) {}
-
             for k in 0..recombine {
                 if let Some(ref mut lowband_in) = lowband {
                     haar1(lowband_in.to_mut(), n >> k, 1 << k);
@@ -1731,10 +1878,9 @@ impl Celt {
 
             blocks >>= recombine;
             n_b <<= recombine;
-            println!("blocks {} N_B {}", blocks, n_b);
+
             while (n_b & 1) == 0 && tf_change < 0 {
                 if let Some(ref mut lowband_in) = lowband {
-                    println!("EDIT");
                     haar1(lowband_in.to_mut(), n_b, blocks);
                 };
 
@@ -1749,7 +1895,6 @@ impl Celt {
             b0 = blocks;
             n_b0 = n_b;
 
-            println!("B0 {}", b0);
             if b0 > 1 {
                 if let Some(ref mut lowband_in) = lowband {
                     deinterleave_hadamard(
@@ -1771,60 +1916,41 @@ impl Celt {
 
         let cache = &CACHE_BITS[CACHE_INDEX[(lm + 1) as usize * MAX_BANDS + band] as usize..];
 
-        let split = if !dualstereo && lm >= 0 && b > (cache[cache[0] as usize] as i32) + 12 && n > 2
-        {
-            n >>= 1;
-            let (split_mid, split_side) = mid_buf.split_at_mut(n);
-
-            mid_buf = split_mid;
-            side_buf = Some(split_side);
+        let split = !dualstereo && lm >= 0 && b > (cache[cache[0] as usize] as i32) + 12 && n > 2;
 
+        if split {
+            n >>= 1;
             lm -= 1;
             if blocks == 1 {
                 fill = (fill & 1) | (fill << 1);
             }
             blocks = (blocks + 1) >> 1;
-            true
-        } else {
-            dualstereo
-        };
+        }
 
-        println!("split {} blocks {} lm {}", split as u8, blocks, lm);
-
-        // TODO: move this code out
-        let mut cm = if let Some(side_buf) = side_buf {
-            let info = self.compute_theta(
-                rd,
-                band,
-                lm as usize,
-                n,
-                b as usize,
-                b0,
-                blocks,
-                dualstereo,
-                fill,
-            );
+        let mut cm = if split || dualstereo {
+            let (mid_buf, side_buf) = match y {
+                Some(y) => (&mut x[..], y),
+                None => x.split_at_mut(n),
+            };
+
+            let orig_fill = fill;
             let BandInfo {
                 itheta,
-                inv: _,
+                inv,
                 mid,
                 side,
                 mut delta,
                 qalloc,
-                fill: _,
-            } = info;
+                fill,
+            } = self.compute_theta(rd, band, lm, n, b, b0, blocks, dualstereo, fill);
 
             b -= qalloc as i32;
 
-            println!(
-                "itheta {} delta {} n {} dualstereo {}",
-                itheta, delta, n, dualstereo as usize
-            );
             /* This is a special case for N=2 that only works for stereo and takes
             advantage of the fact that mid and side are orthogonal to encode
             the side with just one bit. */
             let cm = if n == 2 && dualstereo {
-                let sbits = if itheta != 0 || itheta != 16384 {
+                let sbits = if itheta != 0 && itheta != 16384 {
                     1 << 3
                 } else {
                     0
@@ -1832,14 +1958,19 @@ impl Celt {
                 let mbits = b - sbits;
                 self.remaining2 -= qalloc as i32 + sbits;
 
+                let sign = if sbits != 0 {
+                    1 - 2 * rd.rawbits(1) as i32
+                } else {
+                    1
+                } as f32;
+
                 let (mid_buf2, side_buf2) = if itheta > 8192 {
                     (&mut side_buf[..], &mut mid_buf[..])
                 } else {
                     (&mut mid_buf[..], &mut side_buf[..])
                 };
 
-                let sign = if sbits != 0 { 1 - 2 * rd.rawbits(1) } else { 1 } as f32;
-
+                // orig_fill is used since itheta == 16384 clears the low bits we want to fold
                 let cm = self.decode_band(
                     rd,
                     band,
@@ -1848,16 +1979,23 @@ impl Celt {
                     n,
                     mbits,
                     blocks,
-                    lowband.as_ref().map(|c| c.as_ref()),
-                    lowband_out.as_deref_mut(),
+                    lowband,
+                    lowband_out.take(),
                     lm,
                     level,
                     gain,
-                    fill,
+                    orig_fill,
                 );
                 side_buf2[0] = -sign * mid_buf2[1];
-                // TODO complete the mapping
-                //
+                side_buf2[1] = sign * mid_buf2[0];
+
+                for (x, y) in mid_buf[..2].iter_mut().zip(side_buf[..2].iter_mut()) {
+                    let m = *x * mid;
+                    let s = *y * side;
+                    *x = m - s;
+                    *y = m + s;
+                }
+
                 cm
             } else {
                 let mut next_lowband2 = None;
@@ -1866,14 +2004,15 @@ impl Celt {
 
                 if b0 > 1 && !dualstereo && (itheta & 0x3fff) != 0 {
                     if itheta > 8192 {
+                        // Rough approximation for pre-echo masking
                         delta -= delta >> (4 - lm);
                     } else {
+                        // Corresponds to a forward-masking slope of 1.5 dB per 10 ms
                         delta = (delta + (n << 3 >> (5 - lm)) as i16).min(0)
                     }
                 }
 
-                println!("delta {delta}");
-                let mut mbits = ((b - delta as i32) / 2).clamp(0, b);
+                let mut mbits = ((b - delta as i32) / 2).min(b).max(0);
                 let mut sbits = b - mbits;
 
                 self.remaining2 -= qalloc as i32;
@@ -1886,16 +2025,20 @@ impl Celt {
                     }
                 }
 
-                let mut next_level = 0;
+                let mut next_level = level;
 
+                // Only stereo passes on lowband_out, the mono split handles it at the end
                 if dualstereo {
-                    next_lowband_out1 = lowband_out;
+                    next_lowband_out1 = lowband_out.take();
                 } else {
                     next_level += 1;
                 }
 
+                // In stereo mode the mid is not scaled, it is needed normalized for folding
+                let mid_gain = if dualstereo { 1.0 } else { gain * mid };
+                let side_shift = if dualstereo { 0 } else { b0 >> 1 };
+
                 let rebalance = self.remaining2;
-                let side_shift = if dualstereo { b0 >> 1 } else { 0 };
                 if mbits >= sbits {
                     let mut cm = self.decode_band(
                         rd,
@@ -1909,7 +2052,7 @@ impl Celt {
                         next_lowband_out1,
                         lm,
                         next_level,
-                        if dualstereo { 1.0 } else { gain * mid },
+                        mid_gain,
                         fill,
                     );
                     let rebalance = mbits - (rebalance - self.remaining2);
@@ -1967,7 +2110,7 @@ impl Celt {
                         next_lowband_out1,
                         lm,
                         next_level,
-                        gain * mid,
+                        mid_gain,
                         fill,
                     );
 
@@ -1975,27 +2118,25 @@ impl Celt {
                 }
             };
 
-            todo!("split");
+            if dualstereo {
+                if n != 2 {
+                    stereo_merge(mid_buf, side_buf, mid, n);
+                }
+                if inv {
+                    side_buf[..n].iter_mut().for_each(|v| *v = -*v);
+                }
+            }
+
             cm
         } else {
-            self.decode_band_no_split(
-                rd,
-                mid_buf,
-                lowband.as_ref().map(|c| c.as_ref()),
-                n,
-                blocks,
-                gain,
-                cache,
-                b,
-                fill,
-            )
+            self.decode_band_no_split(rd, x, lowband, n, blocks, gain, cache, b, fill)
         };
 
-        if level == 0 {
+        if !dualstereo && level == 0 {
             if b0 > 1 {
                 interleave_hadamard(
                     &mut self.scratch,
-                    mid_buf,
+                    x,
                     n_b >> recombine,
                     b0 << recombine,
                     longblocks,
@@ -2008,27 +2149,24 @@ impl Celt {
                 blocks >>= 1;
                 n_b <<= 1;
                 cm |= cm >> blocks;
-                haar1(mid_buf, n_b, blocks);
+                haar1(x, n_b, blocks);
             }
 
             for k in 0..recombine {
                 cm = BIT_DEINTERLEAVE[cm as usize] as usize;
-                haar1(mid_buf, n0 >> k, 1 << k);
+                haar1(x, n0 >> k, 1 << k);
             }
 
             blocks <<= recombine;
 
             if let Some(lowband_out) = lowband_out {
                 let n = (n0 as f32).sqrt();
-                println!("Lowband_out");
-                for (v, &m) in lowband_out[..n0].iter_mut().zip(mid_buf[..n0].iter()) {
+                for (v, &m) in lowband_out[..n0].iter_mut().zip(x[..n0].iter()) {
                     *v = n * m;
-                    println!("{:.08}", *v);
                 }
             }
 
             cm &= (1 << blocks) - 1;
-            println!("cm {}", cm);
         }
 
         cm
@@ -2055,7 +2193,11 @@ impl Celt {
             let band_size = (FREQ_RANGE[i] as usize) << lm;
 
             let x = &mut coeff0[band_offset..];
-            let y = &mut coeff1[band_offset..];
+            let y = if self.stereo_pkt {
+                Some(&mut coeff1[band_offset..])
+            } else {
+                None
+            };
 
             let consumed = rd.tell_frac() as i32;
 
@@ -2063,11 +2205,10 @@ impl Celt {
                 self.remaining -= consumed;
             }
 
-            self.remaining2 = (rd.available_frac() - 1 - self.anticollapse_bit) as i32;
+            self.remaining2 = (rd.len() * 8) as i32 - consumed - 1 - self.anticollapse_bit as i32;
 
-            let b = if i <= self.codedband - 1 {
-                println!("rem {} rem2 {}", self.remaining, self.remaining2);
-                let remaining = self.remaining / ((self.codedband - 1).min(3) as i32);
+            let b = if i < self.codedband {
+                let remaining = self.remaining / ((self.codedband - i).min(3) as i32);
                 (self.remaining2 + 1)
                     .min(self.pulses[i] + remaining)
                     .max(0)
@@ -2076,24 +2217,35 @@ impl Celt {
                 0
             };
 
-            println!("b {}", b);
-
-            if FREQ_BANDS[i] as i32 - FREQ_RANGE[i] as i32 >= FREQ_BANDS[band.start] as i32
+            if (FREQ_BANDS[i] as i32 - FREQ_RANGE[i] as i32 >= FREQ_BANDS[band.start] as i32
+                || i == band.start + 1)
                 && (update_lowband || lowband_offset == 0)
             {
                 lowband_offset = i;
             }
 
+            // Duplicate enough of the first band to be able to fold the second one,
+            // it is a no-op unless the first two bands differ in size (hybrid).
+            if i == band.start + 1 {
+                let start = (FREQ_BANDS[band.start] as usize) << lm;
+                let n1 = (FREQ_RANGE[band.start] as usize) << lm;
+                let n2 = (FREQ_RANGE[i] as usize) << lm;
+
+                if n2 > n1 {
+                    let src = start + 2 * n1 - n2..start + n1;
+                    norm_mid.copy_within(src.clone(), start + n1);
+                    if self.dual_stereo {
+                        norm_side.copy_within(src, start + n1);
+                    }
+                }
+            }
+
             let mut cm = [0, 0];
             let effective_lowband = if lowband_offset != 0
                 && (self.spread != SPREAD_AGGRESSIVE || self.blocks > 1 || self.tf_change[i] < 0)
             {
-                let effective_lowband =
-                    FREQ_BANDS[band.start].max(FREQ_BANDS[lowband_offset] - FREQ_RANGE[i]);
-                println!(
-                    "effective_lowband {} off {} range {}",
-                    effective_lowband, lowband_offset, FREQ_RANGE[i]
-                );
+                let effective_lowband = FREQ_BANDS[band.start]
+                    .max(FREQ_BANDS[lowband_offset].saturating_sub(FREQ_RANGE[i]));
                 let mut foldstart = lowband_offset;
 
                 for (e, &v) in FREQ_BANDS[..lowband_offset].iter().enumerate().rev() {
@@ -2113,7 +2265,6 @@ impl Celt {
                         break;
                     }
                 }
-                println!("fold {} {}", foldstart, foldend);
 
                 for j in foldstart..foldend {
                     cm[0] |= self.frames[0].collapse_masks[j] as usize;
@@ -2128,34 +2279,39 @@ impl Celt {
                 None
             };
 
-            println!("cm {} {}", cm[0], cm[1]);
-
             if self.dual_stereo && i == self.intensity_stereo {
                 self.dual_stereo = false;
-                for j in (FREQ_BANDS[band.start] << lm) as usize..band_offset as usize {
+                for j in (FREQ_BANDS[band.start] as usize) << lm..band_offset {
                     norm_mid[j] = (norm_mid[j] + norm_side[j]) / 2.0;
                 }
             }
 
-            //            let mut lowband_scratch: [f32; 8 * 22] = unsafe { mem::uninitialized() };
+            // The lowband may overlap the output of the current band
+            let mut lowband_scratch = [[0f32; 8 * 22]; 2];
 
-            let lowband_off = effective_lowband.map(|e| (e << lm) as usize);
+            let lowband_off = effective_lowband.map(|e| (e as usize) << lm);
             let lowband_out_off = if i != band.end {
                 Some(band_offset)
             } else {
                 None
             };
 
-            let (lowband_mid, lowband_mid_out) = norm_mid.split_at_mut(band_offset);
             let n = band_size;
+            let [scratch_mid, scratch_side] = &mut lowband_scratch;
 
-            let lowband_mid = lowband_off.map(|off| &lowband_mid[off..off + n]);
+            let lowband_mid = lowband_off.map(|off| {
+                scratch_mid[..n].copy_from_slice(&norm_mid[off..off + n]);
+                &scratch_mid[..n]
+            });
+            let (_, lowband_mid_out) = norm_mid.split_at_mut(band_offset);
             let lowband_mid_out = lowband_out_off.map(|_| lowband_mid_out);
 
             if self.dual_stereo {
-                let (lowband_side, lowband_side_out) = norm_side.split_at_mut(band_offset);
-
-                let lowband_side = lowband_off.map(|off| &lowband_side[off..off + n]);
+                let lowband_side = lowband_off.map(|off| {
+                    scratch_side[..n].copy_from_slice(&norm_side[off..off + n]);
+                    &scratch_side[..n]
+                });
+                let (_, lowband_side_out) = norm_side.split_at_mut(band_offset);
                 let lowband_side_out = lowband_out_off.map(|_| lowband_side_out);
 
                 cm[0] = self.decode_band(
@@ -2171,14 +2327,13 @@ impl Celt {
                     lm as isize,
                     0,
                     1f32,
-                    // &mut lowband_scratch,
                     cm[0],
                 );
 
                 cm[1] = self.decode_band(
                     rd,
                     i,
-                    y,
+                    y.unwrap(),
                     None,
                     band_size,
                     b / 2,
@@ -2188,7 +2343,6 @@ impl Celt {
                     lm as isize,
                     0,
                     1f32,
-                    // &mut lowband_scratch,
                     cm[1],
                 );
             } else {
@@ -2196,16 +2350,15 @@ impl Celt {
                     rd,
                     i,
                     x,
-                    Some(y),
+                    y,
                     band_size,
-                    b / 2,
+                    b,
                     self.blocks,
                     lowband_mid,
                     lowband_mid_out,
                     lm as isize,
                     0,
                     1f32,
-                    // &mut lowband_scratch,
                     cm[0] | cm[1],
                 );
                 cm[1] = cm[0];
@@ -2219,10 +2372,286 @@ impl Celt {
         }
     }
 
+    // Fill the short blocks that collapsed to zero with noise
+    fn anticollapse(&mut self, coeff: &mut [f32], channel: usize, band: Range<usize>) {
+        let lm = self.lm;
+
+        for i in band {
+            let range = FREQ_RANGE[i] as usize;
+            let n = range << lm;
+
+            // depth in 1/8 bits
+            let depth = (1 + self.pulses[i]) / n as i32;
+            let thresh = (-1.0 - 0.125 * depth as f32).exp2();
+            let sqrt_1 = 1.0 / (n as f32).sqrt();
+
+            let frame = &self.frames[channel];
+            let mut prev = [frame.prev_energy[0][i], frame.prev_energy[1][i]];
+            if !self.stereo_pkt {
+                let other = &self.frames[1];
+                prev[0] = prev[0].max(other.prev_energy[0][i]);
+                prev[1] = prev[1].max(other.prev_energy[1][i]);
+            }
+            let ediff = (frame.energy[i] - prev[0].min(prev[1])).max(0.0);
+            let collapse_mask = frame.collapse_masks[i];
+
+            // short blocks do not have the same energy as long ones,
+            // r needs to be multiplied by 2 or 2*sqrt(2) depending on lm
+            let mut r = (1.0 - ediff).exp2();
+            if lm == 3 {
+                r *= std::f32::consts::SQRT_2;
+            }
+            let r = thresh.min(r) * sqrt_1;
+
+            let x = &mut coeff[(FREQ_BANDS[i] as usize) << lm..][..n];
+            let mut renormalize = false;
+
+            for k in 0..1 << lm {
+                if collapse_mask & (1 << k) == 0 {
+                    for j in 0..range {
+                        x[(j << lm) + k] = if self.rng() & 0x8000 != 0 { r } else { -r };
+                    }
+                    renormalize = true;
+                }
+            }
+
+            // We just added some energy, so we need to renormalize
+            if renormalize {
+                renormalize_vector(x, 1.0);
+            }
+        }
+    }
+
+    // iMDCT, overlap-add, post-filter and de-emphasis of each channel
+    fn synthesis(
+        &mut self,
+        coeffs: [&[f32]; 2],
+        out_buf: &mut [f32],
+        frame_size: usize,
+        transient: bool,
+        scale: f32,
+    ) {
+        let imdct = &mut self.imdct[if transient { 0 } else { self.lm }];
+        let blocks = self.blocks;
+        let blocksize = self.blocksize;
+        let channels = self.stereo as usize + 1;
+
+        for ((frame, coeff), out) in self
+            .frames
+            .iter_mut()
+            .zip(coeffs.iter())
+            .zip(out_buf.chunks_exact_mut(frame_size))
+            .take(channels)
+        {
+            for j in 0..blocks {
+                let dst = &mut frame.buf[1024 + j * blocksize..];
+
+                imdct.imdct15_half(&mut dst[OVERLAP / 2..], &coeff[j..], blocks, scale);
+                window_overlap(dst);
+            }
+
+            frame.postfilter(frame_size);
+
+            frame.deemphasis(out);
+        }
+    }
+
+    // Synthesize noise shaped by the last band energies, fading them out
+    fn conceal_noise(&mut self, out_buf: &mut [f32], frame_size: usize) {
+        let band = self.band.clone();
+        let lm = self.lm;
+        let decay = if self.losses == 0 { 1.5 } else { 0.5 };
+        let channels = self.stereo as usize + 1;
+
+        let mut coeffs = [[0f32; MAX_FRAME_SIZE]; 2];
+
+        for (c, coeff) in coeffs.iter_mut().enumerate().take(channels) {
+            let energy = &mut self.frames[c].energy;
+
+            for e in energy[band.clone()].iter_mut() {
+                *e = (*e - decay).max(ENERGY_SILENCE);
+            }
+
+            for i in band.clone() {
+                let start = (FREQ_BANDS[i] as usize) << lm;
+                let end = (FREQ_BANDS[i + 1] as usize) << lm;
+
+                for v in coeff[start..end].iter_mut() {
+                    *v = (self.rng() as i32 >> 20) as f32;
+                }
+                renormalize_vector(&mut coeff[start..end], 1.0);
+            }
+
+            denormalize(coeff, &self.frames[c].energy, band.clone(), lm);
+        }
+
+        self.blocks = 1;
+        self.blocksize = frame_size;
+
+        let [coeff0, coeff1] = &coeffs;
+        self.synthesis([coeff0, coeff1], out_buf, frame_size, false, 1.0);
+    }
+
+    // Repeat the last pitch period of the output, in the excitation domain
+    fn conceal_pitch(&mut self, out_buf: &mut [f32], frame_size: usize, pitch: usize) {
+        let first = self.losses == 0;
+        let fade = if first { 1.0 } else { 0.8 };
+        let exc_len = (2 * pitch).min(MAX_PERIOD);
+        let ext_len = frame_size + OVERLAP;
+        let channels = self.stereo as usize + 1;
+
+        for (frame, out) in self
+            .frames
+            .iter_mut()
+            .zip(out_buf.chunks_exact_mut(frame_size))
+            .take(channels)
+        {
+            // The history followed by the extrapolated frame and overlap
+            let mut sig = vec![0f32; 1024 + ext_len];
+            sig[..1024].copy_from_slice(&frame.buf[..1024]);
+
+            // Work on the last MAX_PERIOD samples, with some room for the filter memory
+            let mut exc = [0f32; LPC_ORDER + MAX_PERIOD];
+            exc[LPC_ORDER..].copy_from_slice(&sig[1024 - MAX_PERIOD..1024]);
+
+            if first {
+                let mut ac = [0f32; LPC_ORDER + 1];
+                autocorr(&exc[LPC_ORDER..], &mut ac);
+
+                // Add a -40dB noise floor and lag-window to stabilize the recursion
+                ac[0] *= 1.0001;
+                for (i, ac) in ac.iter_mut().enumerate().skip(1) {
+                    *ac -= *ac * (0.008 * 0.008) * (i * i) as f32;
+                }
+
+                levinson(&ac, &mut frame.lpc);
+            }
+
+            let lpc = &frame.lpc;
+
+            // Whiten the last exc_len samples
+            let start = LPC_ORDER + MAX_PERIOD - exc_len;
+            let fir: Vec<f32> = (start..LPC_ORDER + MAX_PERIOD)
+                .map(|i| {
+                    lpc.iter()
+                        .enumerate()
+                        .fold(exc[i], |acc, (j, &a)| acc + a * exc[i - j - 1])
+                })
+                .collect();
+            exc[start..].copy_from_slice(&fir);
+
+            let exc = &exc[LPC_ORDER..];
+
+            // Do not add energy if the signal is decaying
+            let decay = {
+                let len = exc_len / 2;
+                let energy = |e: &[f32]| e.iter().fold(1.0, |acc, &v| acc + v * v);
+                let e1 = energy(&exc[MAX_PERIOD - len..]);
+                let e2 = energy(&exc[MAX_PERIOD - 2 * len..MAX_PERIOD - len]);
+
+                (e1.min(e2) / e2).sqrt()
+            };
+
+            let offset = MAX_PERIOD - pitch;
+            let mut attenuation = fade * decay;
+            let mut s1 = 0.0;
+            let mut j = 0;
+
+            for i in 0..ext_len {
+                if j >= pitch {
+                    j -= pitch;
+                    attenuation *= decay;
+                }
+                sig[1024 + i] = attenuation * exc[offset + j];
+
+                // The energy of the period being repeated
+                let v = sig[1024 - pitch + j];
+                s1 += v * v;
+
+                j += 1;
+            }
+
+            // LPC synthesis, starting from the last output samples
+            for i in 1024..1024 + ext_len {
+                sig[i] = lpc
+                    .iter()
+                    .enumerate()
+                    .fold(sig[i], |acc, (j, &a)| acc - a * sig[i - j - 1]);
+            }
+
+            // Attenuate the frame if the synthesis gained energy, silence it
+            // if it exploded
+            let s2: f32 = sig[1024..].iter().map(|&v| v * v).sum();
+
+            #[allow(clippy::neg_cmp_op_on_partial_ord)]
+            let exploded = !(s1 > 0.2 * s2);
+
+            if exploded {
+                sig[1024..].iter_mut().for_each(|v| *v = 0.0);
+            } else if s1 < s2 {
+                let ratio = ((s1 + 1.0) / (s2 + 1.0)).sqrt();
+
+                for (i, v) in sig[1024..].iter_mut().enumerate() {
+                    *v *= if i < OVERLAP {
+                        1.0 - WINDOW[i] * (1.0 - ratio)
+                    } else {
+                        ratio
+                    };
+                }
+            }
+
+            // Undo the post-filter on the overlap, the next frame applies it again
+            let pf = &frame.pf;
+            let t = pf.period;
+            let [g0, g1, g2] = pf.gains;
+            let off = 1024 + frame_size;
+            let mut etmp = [0f32; OVERLAP];
+
+            for (i, e) in etmp.iter_mut().enumerate() {
+                let k = off + i - t;
+                *e = sig[off + i]
+                    - g0 * sig[k]
+                    - g1 * (sig[k - 1] + sig[k + 1])
+                    - g2 * (sig[k - 2] + sig[k + 2]);
+            }
+
+            // Fold the overlap as the iMDCT would, so it blends with the next frame
+            frame.buf[..1024].copy_from_slice(&sig[frame_size..off]);
+            for i in 0..OVERLAP / 2 {
+                frame.buf[1024 + i] =
+                    WINDOW[i] * etmp[OVERLAP - 1 - i] + WINDOW[OVERLAP - 1 - i] * etmp[i];
+            }
+
+            frame.deemphasis(out);
+        }
+    }
+
+    /// Conceal a lost frame extrapolating the previous ones
+    ///
+    /// The first losses repeat the last pitch period, the following ones
+    /// fill the bands with noise fading out.
+    pub fn conceal(&mut self, out_buf: &mut [f32], frame_duration: FrameDuration) {
+        let frame_size = frame_duration as usize;
+
+        self.lm = (frame_size / SHORT_BLOCKSIZE).celt_ilog2() - 1;
+
+        // Multiples of the post-filter period are as good as the period
+        let period = self.frames[0].pf.period;
+        let pitch = period * ((PLC_PITCH_LAG_MIN + period - 1) / period.max(1));
+
+        if self.losses >= PLC_MAX_LOSSES || self.band.start != 0 || self.skip_plc || pitch == 0 {
+            self.conceal_noise(out_buf, frame_size);
+        } else {
+            self.conceal_pitch(out_buf, frame_size, pitch);
+        }
+
+        self.losses += 1;
+    }
+
     pub fn decode(
         &mut self,
         rd: &mut RangeDecoder,
-        _out_buf: &mut [f32],
+        out_buf: &mut [f32],
         frame_duration: FrameDuration,
         band: Range<usize>,
     ) {
@@ -2232,22 +2661,23 @@ impl Celt {
 
         self.lm = (frame_size / SHORT_BLOCKSIZE).celt_ilog2() - 1;
 
-        println!("framebits {} tell {}", rd.len(), rd.tell());
+        // The pitch-based concealment needs two packets in a row
+        self.skip_plc = self.losses != 0;
 
-        let silence = if rd.available() > 0 {
+        let silence = if rd.tell() >= rd.len() {
+            true
+        } else if rd.tell() == 1 {
             rd.decode_logp(15)
         } else {
-            true
+            false
         };
 
-        println!("silence {}", silence);
-
         if silence {
             // Pretend we are at the end of the buffer
             rd.to_end();
         }
 
-        self.reset_gains();
+        self.reset_postfilter();
         if band.start == 0 && rd.available() >= 16 {
             self.parse_postfilter(rd);
         }
@@ -2258,8 +2688,6 @@ impl Celt {
             false
         };
 
-        println!("duration {}, transient {}", self.lm, transient);
-
         self.blocks = if transient { 1 << self.lm } else { 1 };
         self.blocksize = frame_size / self.blocks;
 
@@ -2279,53 +2707,121 @@ impl Celt {
 
         self.decode_coarse_energy(rd, band.clone());
 
-        println!(
-            "available {} tell {} frac {}",
-            rd.available(),
-            rd.tell(),
-            rd.tell_frac()
-        );
-
         self.decode_tf_changes(rd, band.clone(), transient);
 
-        println!(
-            "available {} tell {} frac {}",
-            rd.available(),
-            rd.tell(),
-            rd.tell_frac()
-        );
-
         self.decode_allocation(rd, band.clone());
 
-        println!(
-            "available {} tell {} frac {}",
-            rd.available(),
-            rd.tell(),
-            rd.tell_frac()
-        );
-
         self.decode_fine_energy(rd, band.clone());
 
         // TODO: Consider spinning out a Band struct
         let mut coeff0 = [0f32; MAX_FRAME_SIZE];
         let mut coeff1 = [0f32; MAX_FRAME_SIZE];
 
-        println!(
-            "available {} tell {} frac {}",
-            rd.available(),
-            rd.tell(),
-            rd.tell_frac()
+        self.decode_bands(rd, band.clone(), &mut coeff0, &mut coeff1);
+
+        let anticollapse = self.anticollapse_bit != 0 && rd.rawbits(1) != 0;
+
+        self.decode_final_energy(rd, band.clone());
+
+        if anticollapse {
+            self.anticollapse(&mut coeff0, 0, band.clone());
+            if self.stereo_pkt {
+                self.anticollapse(&mut coeff1, 1, band.clone());
+            }
+        }
+
+        denormalize(&mut coeff0, &self.frames[0].energy, band.clone(), self.lm);
+        if self.stereo_pkt {
+            denormalize(&mut coeff1, &self.frames[1].energy, band.clone(), self.lm);
+        }
+
+        let imdct_scale = if self.stereo_pkt && !self.stereo {
+            // stereo to mono downmix
+            coeff0
+                .iter_mut()
+                .zip(coeff1.iter())
+                .for_each(|(c0, &c1)| *c0 += c1);
+            0.5
+        } else {
+            if !self.stereo_pkt && self.stereo {
+                coeff1 = coeff0;
+            }
+            1.0
+        };
+
+        if silence {
+            for frame in self.frames.iter_mut() {
+                frame.energy = [ENERGY_SILENCE; MAX_BANDS];
+            }
+            coeff0 = [0f32; MAX_FRAME_SIZE];
+            coeff1 = [0f32; MAX_FRAME_SIZE];
+        }
+
+        self.synthesis(
+            [&coeff0, &coeff1],
+            out_buf,
+            frame_size,
+            transient,
+            imdct_scale,
         );
 
-        self.decode_bands(rd, band.clone(), &mut coeff0, &mut coeff1);
+        if !self.stereo_pkt {
+            self.frames[1].energy = self.frames[0].energy;
+        }
+
+        for frame in self.frames.iter_mut() {
+            if !transient {
+                frame.prev_energy[1] = frame.prev_energy[0];
+                frame.prev_energy[0] = frame.energy;
+            } else {
+                frame.prev_energy[0]
+                    .iter_mut()
+                    .zip(frame.energy.iter())
+                    .for_each(|(p, &e)| *p = p.min(e));
+            }
+
+            for i in (0..band.start).chain(band.end..MAX_BANDS) {
+                frame.prev_energy[0][i] = ENERGY_SILENCE;
+                frame.prev_energy[1][i] = ENERGY_SILENCE;
+                frame.energy[i] = 0.0;
+            }
+        }
 
         self.seed = rd.range as u32;
+        self.band = band;
+        self.losses = 0;
     }
 }
 
 #[cfg(test)]
 mod test {
 
+    #[test]
+    fn reset_postfilter() {
+        use crate::entropy::RangeDecoder;
+        use crate::packet::Packet;
+
+        let mut celt = super::Celt::new(false);
+        let mut decode = |data: &[u8]| {
+            let p = Packet::from_slice(data).unwrap();
+            let mut out = vec![0f32; 960];
+            celt.setup(&p);
+            celt.decode(
+                &mut RangeDecoder::new(p.frames[0]),
+                &mut out,
+                p.frame_duration,
+                0..21,
+            );
+            celt.frames[0].pf.period
+        };
+
+        // A 20ms CELT packet of a 150Hz tone, post-filtered
+        assert_eq!(decode(&[0xb8, 0xd7, 0x5b, 0xd7, 0x9b, 0xb9, 0x4b, 0x27, 0x67, 0x93, 0xc7, 0xfb, 0x44, 0x23, 0x48, 0xf9, 0x3f, 0x39, 0xff, 0xea, 0x2f, 0xf5, 0x43, 0x38, 0x6e, 0x1e, 0x80, 0x04, 0xa6, 0x41]), 320);
+
+        // A silent one, without post-filter nor a period left for the concealment
+        assert_eq!(decode(&[0xf8, 0xff, 0xff]), 0);
+    }
+
     // TODO compare 2 different impls
     #[test]
     fn stereo_merge() {
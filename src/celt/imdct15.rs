@@ -7,6 +7,7 @@ pub struct IMDCT15 {
     len4: usize,
 
     tmp: Vec<Complex32>,
+    out: Vec<Complex32>,
     exptab: Vec<Vec<Complex32>>,
     twiddle: Vec<Complex32>,
 }
@@ -81,13 +82,13 @@ fn fft5(inp: &[Complex32], stride: usize) -> [Complex32; 5] {
 }
 
 impl IMDCT15 {
-    fn new(n: usize) -> Self {
+    /// Inverse MDCT producing `15 << n` samples out of `15 << n` coefficients
+    pub fn new(n: usize) -> Self {
         use std::f32::consts::PI;
         let len2 = p2len(n);
         let len = len2 * 2;
         let len4 = len2 / 2;
 
-        let mut tmp = Vec::with_capacity(len * 2);
         let twiddle = (len4..len2)
             .map(|i| {
                 let v = 2f32 * PI * (i as f32 + 0.125) / len as f32;
@@ -112,13 +113,12 @@ impl IMDCT15 {
             exptab[0].push(v);
         }
 
-        tmp.resize(len * 2, Complex32::default());
-
         IMDCT15 {
             n,
             len2,
             len4,
-            tmp,
+            tmp: vec![Complex32::default(); len4],
+            out: vec![Complex32::default(); len4],
             exptab,
             twiddle,
         }
@@ -138,65 +138,70 @@ impl IMDCT15 {
 
             let e1 = t1 * exptab[i + 5];
             let e2 = t2 * exptab[2 * (i + 5)];
-            out[i] = t0 + e1 + e2;
+            out[i + 5] = t0 + e1 + e2;
 
             let e1 = t1 * exptab[i + 10];
             let e2 = t2 * exptab[2 * i + 5];
-            out[i] = t0 + e1 + e2;
+            out[i + 10] = t0 + e1 + e2;
         }
     }
 
     fn fft_calc(&self, n: usize, out: &mut [Complex32], inp: &[Complex32], stride: usize) {
         if n > 0 {
             let exptab = &self.exptab[n];
-            let len2 = p2len(n);
+            let len2 = p2len(n - 1);
 
-            self.fft_calc(n - 1, &mut out[..], &inp, stride * 2);
+            self.fft_calc(n - 1, &mut out[..len2], &inp, stride * 2);
             self.fft_calc(n - 1, &mut out[len2..], &inp[stride..], stride * 2);
 
             for i in 0..len2 {
                 let e = out[i + len2] * exptab[i];
                 let o = out[i];
 
-                out[i + len2] = o + e;
-                out[i] += e;
+                out[i + len2] = o - e;
+                out[i] = o + e;
             }
         } else {
             self.fft15(out, inp, stride);
         }
     }
 
-    // Assume out is aligned at least by 64
+    /// Compute the middle half of the inverse transform of `inp`,
+    /// reading one coefficient every `stride`.
+    ///
+    /// `out` must hold at least `15 << n` samples.
     pub fn imdct15_half(&mut self, out: &mut [f32], inp: &[f32], stride: usize, scale: f32) {
-        let mut dst: Vec<Complex32> = unsafe {
-            Vec::from_raw_parts(
-                mem::transmute(out.as_mut_ptr()),
-                out.len() / 2,
-                out.len() / 2,
-            )
-        };
         let len8 = self.len4 / 2;
         let start = (self.len2 - 1) * stride;
 
-        for (i, t) in self.tmp.iter_mut().enumerate() {
+        for (i, (t, tw)) in self.tmp.iter_mut().zip(self.twiddle.iter()).enumerate() {
             let re = inp[start - 2 * stride * i];
             let im = inp[2 * stride * i];
-            *t = Complex32::new(re, im) * self.twiddle[i];
+            *t = Complex32::new(re, im) * tw;
         }
 
-        self.fft_calc(self.n, &mut dst, &self.tmp, 1);
+        let mut dst = mem::replace(&mut self.out, Vec::new());
+
+        self.fft_calc(self.n - 1, &mut dst, &self.tmp, 1);
 
         for i in 0..len8 {
             let decr = len8 - i - 1;
             let incr = len8 + i;
             let re0im1 = Complex32::new(dst[decr].im, dst[decr].re)
-                * Complex32::new(self.twiddle[decr].im, self.twiddle[decr].im);
+                * Complex32::new(self.twiddle[decr].im, self.twiddle[decr].re);
             let re1im0 = Complex32::new(dst[incr].im, dst[incr].re)
-                * Complex32::new(self.twiddle[incr].im, self.twiddle[incr].im);
+                * Complex32::new(self.twiddle[incr].im, self.twiddle[incr].re);
 
             dst[decr] = Complex32::new(re0im1.re, re1im0.im).scale(scale);
             dst[incr] = Complex32::new(re1im0.re, re0im1.im).scale(scale);
         }
+
+        for (o, z) in out.chunks_exact_mut(2).zip(dst.iter()) {
+            o[0] = z.re;
+            o[1] = z.im;
+        }
+
+        self.out = dst;
     }
 }
 
@@ -210,6 +215,30 @@ mod test {
         println!("{:#?}", imdct);
     }
 
+    #[test]
+    fn imdct15_half() {
+        use std::f64::consts::PI;
+
+        for n in 3..=6 {
+            let mut imdct = IMDCT15::new(n);
+            let len2 = p2len(n);
+            let inp: Vec<f32> = (0..len2).map(|i| ((i * 7 + 3) % 11) as f32 - 5.0).collect();
+            let mut out = vec![0f32; len2];
+
+            imdct.imdct15_half(&mut out, &inp, 1, 1.0);
+
+            for (k, o) in out.iter().enumerate() {
+                let t = (k + len2 / 2) as f64 + 0.5 + len2 as f64 / 2.0;
+                let r: f64 = inp
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &v)| v as f64 * (PI / len2 as f64 * t * (i as f64 + 0.5)).cos())
+                    .sum();
+                assert!((*o as f64 - r).abs() < 1e-3 * r.abs().max(1.0));
+            }
+        }
+    }
+
     #[test]
     fn fft5() {
         let a: Vec<Complex32> = (0..15)
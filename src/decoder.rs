@@ -1,11 +1,18 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
 use crate::codec::decoder::*;
 use crate::codec::error::*;
-use crate::data::frame::ArcFrame;
+use crate::data::audiosample::formats::F32;
+use crate::data::audiosample::{ChannelMap, ChannelType};
+use crate::data::frame::*;
 use crate::data::packet::Packet as AVPacket;
+use crate::data::timeinfo::TimeInfo;
 
+use crate::header::OpusHead;
 use crate::packet::*;
 
-use crate::celt::Celt;
+use crate::celt::{Celt, WINDOW};
 use crate::entropy::*;
 use crate::silk::Silk;
 
@@ -13,44 +20,383 @@ struct Des {
     descr: Descr,
 }
 
-struct Dec {
+/// Opus decoder
+pub struct Dec {
     extradata: Option<Vec<u8>>,
-    silk: Option<Silk>,
-    celt: Option<Celt>,
+    info: AudioInfo,
+    pending: VecDeque<ArcFrame>,
+    streams: Vec<StreamDec>,
+    coupled_streams: usize,
+    // Decoded channel feeding each output channel, 255 for silence
+    mapping: Vec<u8>,
+    // Output channels as a mix of the decoded ones, in place of the mapping
+    demixing: Option<Vec<f32>>,
+    // Linear output gain
+    gain: f32,
+    // Samples to discard at the start of the stream
+    pre_skip: usize,
+    // Samples still to discard before the next output
+    skip: usize,
+    // Samples to discard at the end of the next output
+    discard: usize,
+    // Granule position at the end of the next output
+    end: Option<u64>,
+    // Granule position at the end of the last output
+    position: Option<u64>,
+}
+
+// Decoder of a single elementary stream
+struct StreamDec {
+    channels: usize,
+    silk: Silk,
+    celt: Celt,
+
+    // Duration of the last frame
+    frame_duration: Option<FrameDuration>,
+    prev_mode: Option<Mode>,
+    // The previous frame ended with a redundant CELT frame
+    prev_redundancy: bool,
+}
+
+// Cross-fade from `from` to `to` with the squared CELT window, writing to `to`
+fn smooth_fade(from: &[f32], to: &mut [f32]) {
+    for ((t, &f), &w) in to.iter_mut().zip(from.iter()).zip(WINDOW.iter()) {
+        let w = w * w;
+        *t = w * *t + (1.0 - w) * f;
+    }
 }
 
 impl Dec {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Dec {
             extradata: None,
-            silk: None,
-            celt: None,
+            info: AudioInfo {
+                samples: 0,
+                sample_rate: 48000,
+                map: ChannelMap::new(),
+                format: Arc::new(*F32),
+                block_len: None,
+            },
+            pending: VecDeque::new(),
+            streams: Vec::new(),
+            coupled_streams: 0,
+            mapping: Vec::new(),
+            demixing: None,
+            gain: 1.0,
+            pre_skip: 0,
+            skip: 0,
+            discard: 0,
+            end: None,
+            position: None,
         }
     }
-}
 
-impl Descriptor for Des {
-    fn create(&self) -> Box<dyn Decoder> {
-        Box::new(Dec::new())
+    fn decode_packet(&mut self, av_pkt: &AVPacket, fec: bool) -> Result<()> {
+        if self.streams.is_empty() {
+            return Err(Error::ConfigurationIncomplete);
+        }
+
+        let pkts = split_multistream(av_pkt.data.as_slice(), self.streams.len())?;
+
+        // Only the first frame carries the LBRR data of the previous packet
+        let duration = |pkt: &Packet| {
+            let frames = if fec { 1 } else { pkt.frames.len() };
+            pkt.frame_duration as usize * frames
+        };
+
+        let samples = duration(&pkts[0]);
+        if pkts.iter().any(|pkt| duration(pkt) != samples) {
+            return Err(Error::InvalidData);
+        }
+
+        // Decode the streams as planes of samples, the coupled streams first
+        let mut planes = vec![0f32; samples * (self.streams.len() + self.coupled_streams)];
+        let mut off = 0;
+        for (st, pkt) in self.streams.iter_mut().zip(pkts.iter()) {
+            let len = samples * st.channels;
+            st.decode(pkt, fec, &mut planes[off..off + len])?;
+            off += len;
+        }
+
+        // The recovered frame ends where the packet carrying it starts
+        let mut t = av_pkt.t.clone();
+        if fec {
+            t.pts = t.pts.map(|pts| pts - samples as i64);
+            t.duration = Some(samples as u64);
+        }
+
+        self.output(&planes, samples, Some(t))
     }
 
-    fn describe<'a>(&'a self) -> &'a Descr {
-        &self.descr
+    // Mix or route the decoded channels to the output ones and queue the frame
+    fn output(&mut self, planes: &[f32], samples: usize, t: Option<TimeInfo>) -> Result<()> {
+        let duration = samples as u64;
+
+        // Place the output in the stream from its end granule position, the
+        // samples past the end are dropped, see
+        // [section-4](https://tools.ietf.org/html/rfc7845#section-4)
+        //
+        // The pre-skip is dropped from the start of the stream whatever its
+        // first granule position, it covers the decoder warm-up.
+        if let Some(end) = self.end.take() {
+            let start = match self.position {
+                Some(position) if position + duration >= end => position,
+                // First output or a gap in the stream
+                _ => end.saturating_sub(duration),
+            };
+            self.discard = (start + duration - end) as usize;
+            self.position = Some(start);
+        }
+        if let Some(ref mut position) = self.position {
+            *position += duration;
+        }
+
+        // Drop the samples still to be skipped from the start of each plane
+        // and the ones to discard from the end
+        let skip = self.skip.min(samples);
+        self.skip -= skip;
+        let len = samples - skip - self.discard.min(samples - skip);
+        self.discard = 0;
+        if len == 0 {
+            return Ok(());
+        }
+
+        // The timestamps are in 48kHz samples, as the output
+        let t = t.map(|mut t| {
+            if len < samples {
+                t.pts = t.pts.map(|pts| pts + skip as i64);
+                t.duration = Some(len as u64);
+            }
+            t
+        });
+
+        let trimmed: Vec<f32>;
+        let (planes, samples) = if len < samples {
+            trimmed = planes
+                .chunks_exact(samples)
+                .flat_map(|plane| plane[skip..skip + len].iter().cloned())
+                .collect();
+            (&trimmed[..], len)
+        } else {
+            (planes, samples)
+        };
+
+        let channels = self.info.map.len();
+        let mut info = self.info.clone();
+        info.samples = samples;
+
+        let mut f = Frame::new_default_frame(info, t);
+        let out: &mut [f32] = f.buf.as_mut_slice(0).map_err(|_| Error::InvalidData)?;
+
+        // The demixing matrix is stored in column-major order, one column
+        // per decoded channel
+        if let Some(ref demixing) = self.demixing {
+            for (plane, column) in planes
+                .chunks_exact(samples)
+                .zip(demixing.chunks_exact(channels))
+            {
+                for (o, &v) in out.chunks_exact_mut(channels).zip(plane) {
+                    o.iter_mut()
+                        .zip(column.iter())
+                        .for_each(|(o, &c)| *o += c * v);
+                }
+            }
+        }
+
+        // Route the decoded channels to the output ones, the unmapped ones
+        // stay silent
+        for (ch, &m) in self.mapping.iter().enumerate() {
+            let plane = match planes.chunks_exact(samples).nth(m as usize) {
+                Some(plane) if m != 255 => plane,
+                _ => continue,
+            };
+
+            for (o, &v) in out.iter_mut().skip(ch).step_by(channels).zip(plane) {
+                *o = v;
+            }
+        }
+
+        if self.gain != 1.0 {
+            out.iter_mut().for_each(|v| *v *= self.gain);
+        }
+
+        // The hybrid sum, the redundant frames and the gain may exceed the
+        // full scale
+        out.iter_mut().for_each(|v| *v = v.clamp(-1.0, 1.0));
+
+        self.pending.push_back(Arc::new(f));
+
+        Ok(())
+    }
+
+    /// Recover the packet preceding `av_pkt` from its LBRR data
+    ///
+    /// Call it in place of `send_packet` for a lost packet, then decode
+    /// `av_pkt` as usual. The recovered frame has the duration of the first
+    /// frame of `av_pkt` and ends at its `pts`, the CELT layer is concealed.
+    pub fn decode_fec(&mut self, av_pkt: &AVPacket) -> Result<()> {
+        self.decode_packet(av_pkt, true)
+    }
+
+    /// Decode `av_pkt` dropping `discard` samples from the end of its output
+    ///
+    /// It is meant for the discard padding signalled by Matroska for the
+    /// last packet of the stream.
+    pub fn send_packet_discard(&mut self, av_pkt: &AVPacket, discard: usize) -> Result<()> {
+        self.discard = discard;
+        let ret = self.decode_packet(av_pkt, false);
+        self.discard = 0;
+
+        ret
+    }
+
+    /// Decode `av_pkt` ending at the granule position `granule`
+    ///
+    /// The granule position counts the 48kHz samples from the start of the
+    /// stream, the pre-skip included. The samples past `granule` are
+    /// dropped, as for the last page of an Ogg stream, while the pre-skip
+    /// is always dropped from the first outputs after `configure` and
+    /// `flush`, even for a stream not starting at 0.
+    pub fn send_packet_granule(&mut self, av_pkt: &AVPacket, granule: u64) -> Result<()> {
+        self.end = Some(granule);
+        let ret = self.decode_packet(av_pkt, false);
+        self.end = None;
+
+        ret
+    }
+
+    /// Number of samples at 48kHz discarded at the start of the stream
+    ///
+    /// It is the pre-skip signalled in the OpusHead, the decoder drops them
+    /// after `configure` and `flush`.
+    pub fn delay(&self) -> usize {
+        self.pre_skip
+    }
+
+    /// Conceal `samples` samples of lost audio
+    ///
+    /// Call it in place of `send_packet` for a lost packet without a following
+    /// one to recover it from. The audio is extrapolated from the previous
+    /// packets, `samples` must be a multiple of 2.5ms.
+    pub fn conceal(&mut self, samples: usize) -> Result<()> {
+        if self.streams.is_empty() {
+            return Err(Error::ConfigurationIncomplete);
+        }
+
+        if samples == 0 || samples % FrameDuration::VeryShort as usize != 0 {
+            return Err(Error::InvalidData);
+        }
+
+        let mut planes = vec![0f32; samples * (self.streams.len() + self.coupled_streams)];
+        let mut off = 0;
+        for st in self.streams.iter_mut() {
+            let len = samples * st.channels;
+            st.conceal(&mut planes[off..off + len]);
+            off += len;
+        }
+
+        self.output(&planes, samples, None)
+    }
+}
+
+impl Default for Dec {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-const OPUS_HEAD_SIZE: usize = 19;
+impl StreamDec {
+    fn new(stereo: bool) -> Self {
+        StreamDec {
+            channels: stereo as usize + 1,
+            silk: Silk::new(stereo),
+            celt: Celt::new(stereo),
+            frame_duration: None,
+            prev_mode: None,
+            prev_redundancy: false,
+        }
+    }
+
+    // Reset the state carried across packets
+    fn flush(&mut self) {
+        self.silk.flush();
+        self.celt.flush();
 
-impl Decoder for Dec {
-    fn set_extradata(&mut self, extra: &[u8]) {
-        self.extradata = Some(Vec::from(extra));
+        self.frame_duration = None;
+        self.prev_mode = None;
+        self.prev_redundancy = false;
+    }
+
+    // Conceal a lost packet in `out`, one plane of samples per channel
+    fn conceal(&mut self, out: &mut [f32]) {
+        let channels = self.channels;
+        let samples = out.len() / channels;
+
+        let frame_size = match self.frame_duration {
+            Some(d) => d as usize,
+            None => return,
+        };
+
+        let mut off = 0;
+        while off < samples {
+            // Extrapolate at most 20ms and the last frame duration at a time,
+            // as libopus does, so the state stays in step with the output
+            let limit = frame_size.min(samples - off);
+            let frame_duration = [
+                FrameDuration::Standard,
+                FrameDuration::Medium,
+                FrameDuration::Short,
+                FrameDuration::VeryShort,
+            ]
+            .iter()
+            .copied()
+            .find(|&d| d as usize <= limit)
+            .unwrap();
+            let len = frame_duration as usize;
+
+            let mut buf = vec![0f32; len * channels];
+
+            if self.prev_mode != Some(Mode::CELT) {
+                // SILK conceals at least 10ms
+                let silk_duration = if len < FrameDuration::Medium as usize {
+                    FrameDuration::Medium
+                } else {
+                    frame_duration
+                };
+                let silk_len = silk_duration as usize;
+                let mut silk_buf = vec![0f32; silk_len * channels];
+
+                self.silk.conceal(silk_duration);
+                self.silk.resample(&mut silk_buf);
+
+                for (b, s) in buf
+                    .chunks_exact_mut(len)
+                    .zip(silk_buf.chunks_exact(silk_len))
+                {
+                    b.copy_from_slice(&s[..len]);
+                }
+            }
+            if self.prev_mode != Some(Mode::SILK) {
+                let mut celt_buf = vec![0f32; len * channels];
+                self.celt.conceal(&mut celt_buf, frame_duration);
+                buf.iter_mut()
+                    .zip(celt_buf.iter())
+                    .for_each(|(o, &c)| *o += c);
+            }
+
+            for (plane, buf) in out.chunks_exact_mut(samples).zip(buf.chunks_exact(len)) {
+                plane[off..off + len].copy_from_slice(buf);
+            }
+
+            off += len;
+        }
+        self.prev_redundancy = false;
     }
-    fn send_packet(&mut self, pkt: &AVPacket) -> Result<()> {
-        let silk = self.silk.as_mut().unwrap();
-        let celt = self.celt.as_mut().unwrap();
-        let pkt = Packet::from_slice(pkt.data.as_slice())?;
 
-        println!("{:?}", pkt);
+    // Decode `pkt` in `out`, one plane of samples per channel
+    fn decode(&mut self, pkt: &Packet, fec: bool, out: &mut [f32]) -> Result<()> {
+        let silk = &mut self.silk;
+        let celt = &mut self.celt;
 
         // Configure the CELT and the SILK decoder with the
         // frame-invariant, per-packet information
@@ -58,30 +404,51 @@ impl Decoder for Dec {
             silk.setup(&pkt);
         }
 
-        if pkt.mode == Mode::CELT {
-            celt.setup(&pkt);
-        }
+        // The SILK packets may carry a redundant CELT frame
+        celt.setup(&pkt);
 
-        if pkt.mode == Mode::HYBRID {
-            //                unimplemented!();
-        }
+        let channels = self.channels;
+        let frame_size = pkt.frame_duration as usize;
+        let samples = out.len() / channels;
+
+        self.frame_duration = Some(pkt.frame_duration);
+
+        // Only the first frame carries the LBRR data of the previous packet
+        let frames = if fec { &pkt.frames[..1] } else { &pkt.frames[..] };
+
+        // Planar scratch buffers, one frame_size run per output channel
+        let mut out_buf = vec![0f32; frame_size * channels];
+        let mut silk_buf = vec![0f32; frame_size * channels];
+
+        // The redundant frames last 5ms, the cross-fades 2.5ms
+        let redundant_size = FrameDuration::Short as usize;
+        let fade_size = FrameDuration::VeryShort as usize;
+        let mut redundant_buf = vec![0f32; redundant_size * channels];
 
         // Decode the frames
         //
         // If a silk or a hybrid frame is preset, decode the silk part first
-        for frame in pkt.frames {
+        for (i, frame) in frames.iter().enumerate() {
             let mut rd = RangeDecoder::new(frame);
+
+            out_buf.iter_mut().for_each(|v| *v = 0f32);
             // println!("Decoding {:?}", frame);
 
             if pkt.mode != Mode::CELT {
-                silk.decode(&mut rd)?;
-            } else {
+                if fec {
+                    silk.decode_fec(&mut rd)?;
+                } else {
+                    silk.decode(&mut rd)?;
+                }
+            } else if !fec {
                 silk.flush();
             }
 
             let size = frame.len();
             let consumed = rd.tell();
-            let redundancy = if pkt.mode == Mode::HYBRID && consumed + 37 <= size * 8 {
+            let mut redundancy = if fec {
+                false
+            } else if pkt.mode == Mode::HYBRID && consumed + 37 <= size * 8 {
                 rd.decode_logp(12)
             } else if pkt.mode == Mode::SILK && consumed + 17 <= size * 8 {
                 true
@@ -89,101 +456,272 @@ impl Decoder for Dec {
                 false
             };
 
-            println!("consumed {} redundancy {}", consumed, redundancy);
+            // The redundant frame is at the end of the frame, see
+            // [section-4.5.1](https://tools.ietf.org/html/rfc6716#section-4.5.1)
+            let mut celt_to_silk = false;
+            let mut redundant_frame: &[u8] = &[];
 
             if redundancy {
-                let redundancy_pos = rd.decode_logp(1);
+                celt_to_silk = rd.decode_logp(1);
 
                 let redundancy_size = if pkt.mode == Mode::HYBRID {
                     rd.decode_uniform(256) + 2
                 } else {
-                    size - (consumed + 7) / 8
+                    size - (rd.tell() + 7) / 8
                 };
 
-                if redundancy_size >= size {
-                    return Err(Error::InvalidData);
+                // A redundant frame overlapping the main one is ignored
+                match size.checked_sub(redundancy_size) {
+                    Some(main_size) if main_size * 8 >= rd.tell() => {
+                        rd.shrink(redundancy_size);
+                        redundant_frame = &frame[main_size..];
+                    }
+                    _ => redundancy = false,
+                }
+            }
+
+            if pkt.mode != Mode::CELT {
+                silk.resample(&mut silk_buf);
+            }
+
+            let band_end = pkt.bandwidth.celt_band();
+
+            // A CELT to SILK transition starts with the redundant frame
+            if redundancy && celt_to_silk {
+                let mut rd = RangeDecoder::new(redundant_frame);
+                celt.decode(
+                    &mut rd,
+                    &mut redundant_buf,
+                    FrameDuration::Short,
+                    0..band_end,
+                );
+            }
+
+            if pkt.mode != Mode::SILK && fec {
+                celt.conceal(&mut out_buf, pkt.frame_duration);
+            } else if pkt.mode != Mode::SILK {
+                let range = if pkt.mode == Mode::HYBRID { 17 } else { 0 }..band_end;
+
+                // The CELT state is stale unless a redundant frame kept it going
+                if self.prev_mode.is_some()
+                    && self.prev_mode.as_ref() != Some(&pkt.mode)
+                    && !self.prev_redundancy
+                {
+                    celt.flush();
                 }
 
-                let _size = size - redundancy_size;
+                celt.decode(&mut rd, &mut out_buf, pkt.frame_duration, range)
+            } else if !fec
+                && self.prev_mode == Some(Mode::HYBRID)
+                && !(redundancy && celt_to_silk && self.prev_redundancy)
+            {
+                // Fade out the CELT layer of the previous hybrid frame
+                let mut fade_buf = vec![0f32; fade_size * channels];
+                let mut rd = RangeDecoder::new(&[0xff, 0xff]);
 
-                println!("redundancy pos {} size {}", redundancy_pos, redundancy_size);
+                celt.decode(
+                    &mut rd,
+                    &mut fade_buf,
+                    FrameDuration::VeryShort,
+                    0..band_end,
+                );
 
-                if redundancy_pos {
-                    // decode_redundancy
-                    // celt.flush()
+                for (out, fade) in out_buf
+                    .chunks_exact_mut(frame_size)
+                    .zip(fade_buf.chunks_exact(fade_size))
+                {
+                    out[..fade_size].copy_from_slice(fade);
                 }
             }
 
-            if pkt.mode != Mode::SILK {
-                let mut out_buf = [0f32; 1024]; // TODO
-                let range =
-                    if pkt.mode == Mode::HYBRID { 17 } else { 0 }..pkt.bandwidth.celt_band();
+            // In hybrid mode the CELT layer covers the bands above the SILK ones
+            if pkt.mode != Mode::CELT {
+                out_buf
+                    .iter_mut()
+                    .zip(silk_buf.iter())
+                    .for_each(|(o, &s)| *o += s);
+            }
 
-                celt.decode(&mut rd, &mut out_buf, pkt.frame_duration, range)
+            // A SILK to CELT transition ends with the redundant frame
+            if redundancy && !celt_to_silk {
+                let mut rd = RangeDecoder::new(redundant_frame);
+
+                celt.flush();
+                celt.decode(
+                    &mut rd,
+                    &mut redundant_buf,
+                    FrameDuration::Short,
+                    0..band_end,
+                );
+
+                for (out, red) in out_buf
+                    .chunks_exact_mut(frame_size)
+                    .zip(redundant_buf.chunks_exact_mut(redundant_size))
+                {
+                    let out = &mut out[frame_size - fade_size..];
+                    smooth_fade(out, &mut red[fade_size..]);
+                    out.copy_from_slice(&red[fade_size..]);
+                }
+            }
+
+            if redundancy
+                && celt_to_silk
+                && (self.prev_mode != Some(Mode::SILK) || self.prev_redundancy)
+            {
+                for (out, red) in out_buf
+                    .chunks_exact_mut(frame_size)
+                    .zip(redundant_buf.chunks_exact(redundant_size))
+                {
+                    out[..fade_size].copy_from_slice(&red[..fade_size]);
+                    smooth_fade(&red[fade_size..], &mut out[fade_size..2 * fade_size]);
+                }
+            }
+
+            // TODO: fade from the concealed previous mode on the transitions
+            // without a redundant frame
+
+            self.prev_mode = Some(pkt.mode.clone());
+            self.prev_redundancy = redundancy && !celt_to_silk;
+
+            // Append the frame to the planes
+            for (plane, buf) in out
+                .chunks_exact_mut(samples)
+                .zip(out_buf.chunks_exact(frame_size))
+            {
+                plane[i * frame_size..(i + 1) * frame_size].copy_from_slice(buf);
             }
         }
 
         Ok(())
     }
+}
+
+impl Descriptor for Des {
+    fn create(&self) -> Box<dyn Decoder> {
+        Box::new(Dec::new())
+    }
+
+    fn describe<'a>(&'a self) -> &'a Descr {
+        &self.descr
+    }
+}
+
+// Vorbis channel order, see
+// [section-5.1.1.2](https://tools.ietf.org/html/rfc7845#section-5.1.1.2)
+fn vorbis_map(channels: usize) -> ChannelMap {
+    use crate::data::audiosample::ChannelType::*;
+
+    let ids: &[ChannelType] = match channels {
+        1 => &[C],
+        2 => &[L, R],
+        3 => &[L, C, R],
+        4 => &[L, R, Ls, Rs],
+        5 => &[L, C, R, Ls, Rs],
+        6 => &[L, C, R, Ls, Rs, LFE],
+        7 => &[L, C, R, Lss, Rss, Cs, LFE],
+        8 => &[L, C, R, Lss, Rss, Ls, Rs, LFE],
+        _ => unreachable!(),
+    };
+
+    let mut map = ChannelMap::new();
+    for &id in ids {
+        map.add_channel(id);
+    }
+    map
+}
+
+// Channels without a speaker position: the ambisonic ones, possibly followed
+// by a non-diegetic stereo pair, see
+// [section-3.1](https://tools.ietf.org/html/rfc8486#section-3.1), and the
+// ones of the family 255
+//
+// There is no ambisonic channel type, they are all marked as center ones.
+fn unpositioned_map(family: u8, channels: usize) -> ChannelMap {
+    use crate::data::audiosample::ChannelType::*;
+
+    let stereo = family != 255 && (1..=15).any(|n| n * n + 2 == channels);
+    let ambisonic = if stereo { channels - 2 } else { channels };
+
+    let mut map = ChannelMap::new();
+    for _ in 0..ambisonic {
+        map.add_channel(C);
+    }
+    if stereo {
+        map.add_channel(L);
+        map.add_channel(R);
+    }
+    map
+}
+
+impl Decoder for Dec {
+    fn set_extradata(&mut self, extra: &[u8]) {
+        self.extradata = Some(Vec::from(extra));
+    }
+    fn send_packet(&mut self, av_pkt: &AVPacket) -> Result<()> {
+        self.decode_packet(av_pkt, false)
+    }
     fn receive_frame(&mut self) -> Result<ArcFrame> {
-        // self.pending.pop_front().ok_or(ErrorKind::MoreDataNeeded.into())
-        //
-        unimplemented!()
+        self.pending.pop_front().ok_or(Error::MoreDataNeeded)
     }
     fn configure(&mut self) -> Result<()> {
-        use crate::bitstream::byteread::get_i16l;
-
-        let channels;
-        let _sample_rate = 48000;
-        let mut gain_db = 0;
-        let mut streams = 1;
-        let mut coupled_streams = 0;
-        let mut mapping: &[u8] = &[0u8, 1u8];
-        let mut channel_map = false;
-
-        if let Some(ref extradata) = self.extradata {
-            channels = *extradata.get(9).unwrap_or(&2) as usize;
-
-            if extradata.len() >= OPUS_HEAD_SIZE {
-                gain_db = get_i16l(&extradata[16..=17]);
-                channel_map = extradata[18] != 0;
-            }
-            if extradata.len() >= OPUS_HEAD_SIZE + 2 + channels {
-                streams = extradata[OPUS_HEAD_SIZE] as usize;
-                coupled_streams = extradata[OPUS_HEAD_SIZE + 1] as usize;
-                if streams + coupled_streams != channels {
-                    unimplemented!()
-                }
-                mapping = &extradata[OPUS_HEAD_SIZE + 2..]
-            } else {
-                if channels > 2 || channel_map {
-                    return Err(Error::ConfigurationInvalid);
-                }
-                if channels > 1 {
-                    coupled_streams = 1;
-                }
-            }
-        } else {
-            return Err(Error::ConfigurationIncomplete);
-        }
+        let head = match self.extradata {
+            Some(ref extradata) => OpusHead::parse(extradata).map_err(|e| match e {
+                Error::Unsupported(_) => e,
+                _ => Error::ConfigurationInvalid,
+            })?,
+            None => return Err(Error::ConfigurationIncomplete),
+        };
 
-        if channels > 2 {
-            unimplemented!() // TODO: Support properly channel mapping
-        } else {
-            // println!("channels {}", channels);
-            self.silk = Some(Silk::new(channels > 1));
-            self.celt = Some(Celt::new(channels > 1));
-            // self.info.map = ChannelMap::default_map(channels);
-        }
+        let channels = head.channels as usize;
+        let streams = head.streams as usize;
+        let coupled_streams = head.coupled_streams as usize;
 
-        //            sample_rate, channels, streams, coupled_streams, mapping
+        // See [section-5.1.1](https://tools.ietf.org/html/rfc7845#section-5.1.1)
+        // and [section-3](https://tools.ietf.org/html/rfc8486#section-3)
+        let demixing = match head.family {
+            0 | 1 | 2 | 255 => None,
+            // Q15 coefficients
+            3 => Some(head.demixing.iter().map(|&c| c as f32 / 32768.0).collect()),
+            family => {
+                return Err(Error::Unsupported(format!(
+                    "channel mapping family {}",
+                    family
+                )))
+            }
+        };
+
+        self.streams = (0..streams)
+            .map(|i| StreamDec::new(i < coupled_streams))
+            .collect();
+        self.coupled_streams = coupled_streams;
+        self.mapping = head.mapping;
+        self.demixing = demixing;
+        // The gain is in Q7.8 dB, see
+        // [section-5.1](https://tools.ietf.org/html/rfc7845#section-5.1)
+        self.gain = 10f32.powf(head.output_gain as f32 / (20.0 * 256.0));
+        self.pre_skip = head.pre_skip as usize;
+        self.skip = self.pre_skip;
+        self.discard = 0;
+        self.position = None;
+        self.info.map = match head.family {
+            0 | 1 => vorbis_map(channels),
+            family => unpositioned_map(family, channels),
+        };
 
         Ok(())
     }
 
     fn flush(&mut self) -> Result<()> {
-        // self.dec.as_mut().unwrap().reset();
-        unimplemented!()
+        self.pending.clear();
+        self.skip = self.pre_skip;
+        self.discard = 0;
+        self.position = None;
+
+        for st in self.streams.iter_mut() {
+            st.flush();
+        }
+
+        Ok(())
     }
 }
 
@@ -207,6 +745,56 @@ mod test {
 
     use interpolate_name::interpolate_test;
 
+    // A 20ms mono CELT fullband packet
+    const CELT_PACKET: &[u8] = &[0xf8, 0x7b, 0x3c, 0xa5, 0x19, 0xe2, 0x54, 0x8f, 0x06, 0xd3];
+
+    fn mono(pre_skip: u16, output_gain: i16) -> OpusHead {
+        OpusHead {
+            channels: 1,
+            pre_skip,
+            output_gain,
+            ..Default::default()
+        }
+    }
+
+    fn decoder(head: &OpusHead) -> Dec {
+        let mut d = Dec::new();
+        d.set_extradata(&head.serialize());
+        d.configure().unwrap();
+        d
+    }
+
+    fn packet(data: &[u8]) -> AVPacket {
+        let mut p = AVPacket::new();
+        p.data = data.to_vec();
+        p
+    }
+
+    // The next frame, its samples interleaved
+    fn receive(d: &mut Dec) -> Vec<f32> {
+        let f = d.receive_frame().unwrap();
+        let len = match f.kind {
+            MediaKind::Audio(ref info) => info.samples * info.map.len(),
+            _ => unreachable!(),
+        };
+        let buf: &[f32] = f.buf.as_slice(0).unwrap();
+        buf[..len].to_vec()
+    }
+
+    // Self-delimited framing of a single frame packet, see
+    // [appendix-B](https://tools.ietf.org/html/rfc6716#appendix-B)
+    fn self_delimited(data: &[u8]) -> Vec<u8> {
+        [&[data[0], data.len() as u8 - 1][..], &data[1..]].concat()
+    }
+
+    // Decode a packet producing a single frame
+    fn decode(d: &mut Dec, p: &AVPacket) -> Vec<f32> {
+        d.send_packet(p).unwrap();
+        let out = receive(d);
+        assert!(d.receive_frame().is_err());
+        out
+    }
+
     fn parse_packet(sample: &PathBuf) {
         let mut ctx = demuxer::Context::new(
             Box::new(MkvDemuxer::new()),
@@ -223,8 +811,18 @@ mod test {
             if let Ok(ev) = ctx.read_event() {
                 match ev {
                     demuxer::Event::NewPacket(p) => {
-                        println!("{:?}", p);
                         d.send_packet(&p).unwrap();
+
+                        let f = d.receive_frame().unwrap();
+                        if let MediaKind::Audio(ref info) = f.kind {
+                            assert_eq!(info.sample_rate, 48000);
+                            assert_eq!(info.map.len(), d.info.map.len());
+                            let buf: &[f32] = f.buf.as_slice(0).unwrap();
+                            assert!(buf.len() >= info.samples * info.map.len());
+                        } else {
+                            unreachable!();
+                        }
+                        assert!(d.receive_frame().is_err());
                     }
                     _ => unreachable!(),
                 }
@@ -232,6 +830,281 @@ mod test {
         }
     }
 
+    #[test]
+    fn multistream() {
+        // 3 channels, mapping family 1, 2 streams, one of them coupled
+        let head = OpusHead {
+            channels: 3,
+            family: 1,
+            streams: 2,
+            coupled_streams: 1,
+            mapping: vec![0, 2, 1],
+            ..Default::default()
+        };
+        let mut d = decoder(&head);
+
+        // A silent stereo stream, self-delimited, and the mono one
+        let mut data = vec![0xfc, 2, 0xff, 0xff];
+        data.extend_from_slice(CELT_PACKET);
+        let out = decode(&mut d, &packet(&data));
+        assert_eq!(d.info.map.len(), 3);
+        assert_eq!(out.len(), 960 * 3);
+
+        // The mono stream feeds the center channel
+        let center = decode(&mut decoder(&mono(0, 0)), &packet(CELT_PACKET));
+        assert!(center.iter().any(|&v| v != 0.0));
+        for (o, &c) in out.chunks_exact(3).zip(center.iter()) {
+            assert_eq!(o, [0.0, c, 0.0]);
+        }
+
+        // The streams must have the same duration
+        assert!(d
+            .send_packet(&packet(&[0xfc, 2, 0xff, 0xff, 0xf0, 0xff, 0xff]))
+            .is_err());
+    }
+
+    #[test]
+    fn ambisonics() {
+        let head = |family, channels, streams, coupled_streams| OpusHead {
+            channels,
+            family,
+            streams,
+            coupled_streams,
+            mapping: (0..channels).collect(),
+            ..Default::default()
+        };
+
+        let mut d = Dec::new();
+
+        // First order plus the stereo pair, then a count not matching any order
+        d.set_extradata(&head(2, 6, 3, 3).serialize());
+        assert!(d.configure().is_ok());
+        d.set_extradata(&head(2, 5, 3, 2).serialize());
+        assert!(d.configure().is_err());
+
+        let mono_out = decode(&mut decoder(&mono(0, 0)), &packet(CELT_PACKET));
+        let mono_stream = self_delimited(CELT_PACKET);
+
+        // The mapping routes the mono stream to the fourth channel
+        let mut d = decoder(&OpusHead {
+            mapping: vec![255, 1, 255, 0, 255, 255],
+            ..head(2, 6, 2, 0)
+        });
+        let data = [&mono_stream[..], &[0xf8, 0xff, 0xff]].concat();
+        let out = decode(&mut d, &packet(&data));
+        assert_eq!(out.len(), 960 * 6);
+        for (o, &v) in out.chunks_exact(6).zip(mono_out.iter()) {
+            assert_eq!(o, [0.0, 0.0, 0.0, v, 0.0, 0.0]);
+        }
+
+        // First order from a silent coupled stream and two mono ones, the
+        // demixing matrix spreads the first mono one over the channels
+        let mut demixing = vec![i16::MAX; 4 * 4];
+        demixing[2 * 4..3 * 4].copy_from_slice(&[16384, -8192, 0, i16::MAX]);
+        let mut d = decoder(&OpusHead {
+            demixing,
+            ..head(3, 4, 3, 1)
+        });
+        let data = [
+            &[0xfc, 2, 0xff, 0xff][..],
+            &mono_stream,
+            &[0xf8, 0xff, 0xff],
+        ]
+        .concat();
+        let out = decode(&mut d, &packet(&data));
+        assert_eq!(out.len(), 960 * 4);
+
+        // The clipped samples aside
+        let full = i16::MAX as f32 / 32768.0;
+        for (o, &v) in out.chunks_exact(4).zip(mono_out.iter()) {
+            if v.abs() < 1.0 {
+                let expected = [0.5 * v, -0.25 * v, 0.0, full * v];
+                for (&o, &e) in o.iter().zip(expected.iter()) {
+                    assert!((o - e).abs() < 1e-6);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn conceal() {
+        let mut d = Dec::new();
+        assert!(d.conceal(960).is_err());
+
+        let mut d = decoder(&mono(0, 0));
+        assert!(d.conceal(100).is_err());
+
+        // A 20ms SILK narrowband packet
+        decode(
+            &mut d,
+            &packet(&[
+                0x08, 0x8b, 0x1a, 0x5c, 0x33, 0xe7, 0x90, 0x42, 0x6d, 0xb1, 0x07,
+            ]),
+        );
+
+        d.conceal(1920).unwrap();
+        let out = receive(&mut d);
+        assert_eq!(out.len(), 1920);
+        assert!(out.iter().all(|v| v.is_finite()));
+
+        // Concealed with the pitch and then noise
+        decode(&mut d, &packet(CELT_PACKET));
+
+        for _ in 0..8 {
+            d.conceal(960).unwrap();
+            assert!(receive(&mut d).iter().all(|v| v.is_finite()));
+        }
+    }
+
+    #[test]
+    fn conceal_in_steps() {
+        // A 60ms SILK narrowband packet, voiced
+        let p = packet(&[
+            0x18, 0xec, 0xff, 0xca, 0xe0, 0x65, 0xcc, 0xaf, 0xc5, 0xc9, 0xf8, 0x85, 0x75, 0x93,
+            0xf8, 0x59, 0x06, 0xa2, 0xbb, 0x18, 0xba, 0xfc, 0x59, 0x75, 0x4b, 0x4b, 0x6a, 0xd1,
+            0x58, 0x9d, 0xa9, 0x87, 0x07, 0x70, 0x96, 0x42, 0x51, 0x37, 0x5c, 0xbb, 0x40, 0xfd,
+            0x44, 0xcc, 0x88,
+        ]);
+
+        let mut whole = decoder(&mono(0, 0));
+        decode(&mut whole, &p);
+        whole.conceal(2880).unwrap();
+        let whole = receive(&mut whole);
+
+        // Concealing a shorter span must not run ahead of the output
+        let mut steps = decoder(&mono(0, 0));
+        decode(&mut steps, &p);
+        let mut out = Vec::new();
+        for _ in 0..3 {
+            steps.conceal(960).unwrap();
+            out.extend(receive(&mut steps));
+        }
+
+        assert_eq!(whole, out);
+        assert!(out.iter().any(|&v| v != 0.0));
+    }
+
+    #[test]
+    fn fec() {
+        // A 40ms SILK narrowband stereo packet, with LBRR data for the side
+        let mut p = packet(&[
+            20, 29, 198, 125, 199, 185, 46, 251, 29, 109, 28, 138, 29, 104, 12, 162, 212, 29, 62,
+            93, 34, 227, 136, 31, 107, 17, 211, 70, 46, 145, 230, 50, 248, 144, 122, 242, 163, 65,
+            253, 20, 172, 61, 127, 116, 153, 110, 130, 47, 127, 49, 155, 64,
+        ]);
+        p.t.pts = Some(1920);
+        p.t.duration = Some(1920);
+
+        let mut d = decoder(&OpusHead::default());
+
+        d.decode_fec(&p).unwrap();
+        let f = d.receive_frame().unwrap();
+        assert_eq!((f.t.pts, f.t.duration), (Some(0), Some(1920)));
+
+        d.send_packet(&p).unwrap();
+        let f = d.receive_frame().unwrap();
+        assert_eq!((f.t.pts, f.t.duration), (Some(1920), Some(1920)));
+    }
+
+    #[test]
+    fn flush() {
+        let p = packet(CELT_PACKET);
+        let mut d = decoder(&mono(0, 0));
+
+        let first = decode(&mut d, &p);
+        assert_ne!(decode(&mut d, &p), first);
+
+        // Nothing pending and the packet decodes as in a new decoder
+        d.send_packet(&p).unwrap();
+        d.flush().unwrap();
+        assert!(d.receive_frame().is_err());
+        assert_eq!(decode(&mut d, &p), first);
+    }
+
+    #[test]
+    fn gain_and_pre_skip() {
+        let p = packet(CELT_PACKET);
+
+        let mut d = decoder(&mono(0, 0));
+        assert_eq!(d.delay(), 0);
+        let plain = decode(&mut d, &p);
+        assert_eq!(plain.len(), 960);
+
+        // Skip 10ms and amplify by 6dB
+        let mut d = decoder(&mono(480, 6 * 256));
+        assert_eq!(d.delay(), 480);
+        let out = decode(&mut d, &p);
+        assert_eq!(out.len(), 480);
+
+        // The output is clamped after the gain
+        let gain = 10f32.powf(6.0 / 20.0);
+        for (&o, &p) in out.iter().zip(plain[480..].iter()) {
+            if p.abs() < 1.0 {
+                assert!((o - (p * gain).clamp(-1.0, 1.0)).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn clamp() {
+        // A loud 20ms hybrid fullband packet
+        let p = packet(&[
+            0x78, 0xa2, 0x78, 0x8c, 0xd8, 0x45, 0xfc, 0xf8, 0x63, 0x22, 0xd5, 0xe1, 0x37, 0xd8,
+            0xb1, 0xe9, 0xaf, 0xa4, 0xba, 0x7e, 0x7c, 0x2c, 0x56, 0x86, 0x82, 0x62, 0x79, 0x17,
+            0x9e, 0x1d, 0x05, 0xd8, 0xf6, 0x46, 0xdd, 0x75, 0x71, 0x8e, 0x1c, 0x9d, 0xc1, 0xc1,
+            0x9e, 0x68, 0x1b, 0x19, 0xbc, 0x8b, 0xae, 0x46, 0x14, 0x29, 0xc6, 0x68, 0x50, 0x0b,
+            0xde, 0xfb, 0x7e, 0x27,
+        ]);
+
+        // Amplified by 12dB, past the full scale
+        let mut d = decoder(&mono(0, 12 * 256));
+        let mut out = Vec::new();
+        for _ in 0..4 {
+            out = decode(&mut d, &p);
+            assert!(out.iter().all(|v| (-1.0..=1.0).contains(v)));
+        }
+        assert!(out.iter().any(|v| v.abs() == 1.0));
+    }
+
+    #[test]
+    fn trim() {
+        let mut p = packet(CELT_PACKET);
+        p.t.pts = Some(0);
+        p.t.duration = Some(960);
+
+        let mut d = decoder(&mono(312, 0));
+
+        // 312 samples of pre-skip, then 100 samples past the end
+        d.send_packet_granule(&p, 960).unwrap();
+        let f = d.receive_frame().unwrap();
+        assert_eq!((f.t.pts, f.t.duration), (Some(312), Some(960 - 312)));
+        d.flush().unwrap();
+        d.send_packet_granule(&p, 960).unwrap();
+        let first = receive(&mut d);
+        assert_eq!(first.len(), 960 - 312);
+        d.send_packet_granule(&p, 1820).unwrap();
+        assert_eq!(receive(&mut d).len(), 860);
+
+        // A stream shorter than its only packet
+        d.flush().unwrap();
+        d.send_packet_granule(&p, 500).unwrap();
+        assert_eq!(receive(&mut d), first[..500 - 312]);
+
+        // A stream cut from a longer one still starts with the pre-skip
+        d.flush().unwrap();
+        d.send_packet_granule(&p, 48000).unwrap();
+        assert_eq!(receive(&mut d), first);
+        d.send_packet_granule(&p, 48960).unwrap();
+        assert_eq!(receive(&mut d).len(), 960);
+
+        d.flush().unwrap();
+        d.send_packet_discard(&p, 200).unwrap();
+        assert_eq!(receive(&mut d), first[..960 - 312 - 200]);
+        d.send_packet_discard(&p, 2000).unwrap();
+        assert!(d.receive_frame().is_err());
+        assert_eq!(decode(&mut d, &p).len(), 960);
+    }
+
     #[interpolate_test(n01, 1)]
     #[interpolate_test(n02, 2)]
     #[interpolate_test(n03, 3)]
@@ -249,7 +1122,6 @@ mod test {
         let mut d = PathBuf::from(p);
         d.push("assets");
         d.push(format!("testvector{:02}.mka", index));
-        println!("path {:?}", d);
         parse_packet(&d);
     }
 }
@@ -58,12 +58,7 @@ impl<'a> UnpaddedBitReadBE<'a> {
 impl<'a> BitReadFill for UnpaddedBitReadBE<'a> {
     #[inline(always)]
     fn can_refill(&self) -> bool {
-        let v = self.index < self.buffer.len();
-
-        if !v {
-            println!("*** Ending *** {}", self.buffer.len());
-        }
-        v
+        self.index < self.buffer.len()
     }
     #[inline(always)]
     fn fill32(&self) -> u64 {
@@ -290,7 +285,6 @@ impl<'a> RangeDecoder<'a> {
     fn normalize(&mut self) {
         while self.range <= CODE_BOT {
             let v = self.bits.get_bits_32(SYM_BITS);
-            println!("val {} range {} normalize {}", self.value, self.range, v);
             let v = v as usize ^ SYM_MAX;
             self.value = ((self.value << SYM_BITS) | v) & (CODE_TOP - 1);
             self.range <<= SYM_BITS;
@@ -315,6 +309,16 @@ impl<'a> RangeDecoder<'a> {
         r
     }
 
+    /// Drop the last `bytes` of the buffer
+    ///
+    /// Used to exclude trailing data, such as a redundant CELT frame, from
+    /// the range of the raw bits. No raw bit must have been read yet.
+    pub fn shrink(&mut self, bytes: usize) {
+        let buf = self.revs.buffer;
+        self.revs = ReverseBitReadLE::new(&buf[..buf.len() - bytes]);
+        self.size_in_bits -= bytes * 8;
+    }
+
     fn update(&mut self, scale: usize, low: usize, high: usize, total: usize) {
         let s = scale * (total - high);
         self.value -= s;
@@ -359,10 +363,6 @@ impl<'a> RangeDecoder<'a> {
         let dist = icdf.dist;
         let (scale, sym) = self.get_scale_symbol(total);
         let k = dist.iter().position(|v| *v > sym).unwrap();
-        println!(
-            "icdf val {} range {} k {}", /* dist {:?}" */
-            self.value, self.range, k, /* dist */
-        );
         let high = dist[k];
         let low = if k > 0 { dist[k - 1] } else { 0 };
         // println!("{} {} decode to {}", scale, sym, k);
@@ -398,12 +398,12 @@ impl<'a> RangeDecoder<'a> {
 
     #[inline(always)]
     pub fn available(&self) -> usize {
-        self.size_in_bits - self.tell()
+        self.size_in_bits.saturating_sub(self.tell())
     }
 
     #[inline(always)]
     pub fn available_frac(&self) -> usize {
-        self.size_in_bits * 8 - self.tell_frac()
+        (self.size_in_bits * 8).saturating_sub(self.tell_frac())
     }
 }
 
@@ -438,7 +438,7 @@ impl<'a> CeltOnly for RangeDecoder<'a> {
         self.update(scale, k, k + 1, total);
 
         if bits > UNI_BITS {
-            k << (bits - UNI_BITS) | self.rawbits(bits - UNI_BITS)
+            (k << (bits - UNI_BITS) | self.rawbits(bits - UNI_BITS)).min(len - 1)
         } else {
             k
         }
@@ -494,7 +494,7 @@ impl<'a> CeltOnly for RangeDecoder<'a> {
         let k = if symbol < k1 {
             symbol / 3
         } else {
-            symbol - (k0 + 1) / 2
+            symbol - (k0 + 1) * 2
         };
 
         if k <= k0 {
@@ -502,7 +502,7 @@ impl<'a> CeltOnly for RangeDecoder<'a> {
         } else {
             self.update(
                 scale,
-                3 * (k + 1) + (k - 1 - k0),
+                3 * (k0 + 1) + (k - 1 - k0),
                 3 * (k0 + 1) + (k - 0 - k0),
                 total,
             );
@@ -538,6 +538,6 @@ impl<'a> CeltOnly for RangeDecoder<'a> {
     }
 
     fn to_end(&mut self) {
-        self.total += self.size_in_bits - self.tell();
+        self.total += self.available();
     }
 }
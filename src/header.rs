@@ -0,0 +1,314 @@
+//! Ogg Opus headers
+//!
+//! Identification and comment headers, as stored in Ogg and as codec
+//! extradata, see [section-5](https://tools.ietf.org/html/rfc7845#section-5)
+
+use crate::bitstream::byteread::{get_i16l, get_u16l, get_u32l};
+use crate::codec::error::*;
+
+const OPUS_HEAD_SIZE: usize = 19;
+
+// Ambisonics use (order + 1)^2 channels, plus an optional non-diegetic
+// stereo pair, up to the 14th order
+fn ambisonic_channels(channels: usize) -> bool {
+    (1..=15)
+        .map(|n| n * n)
+        .any(|n| channels == n || channels == n + 2)
+}
+
+/// Identification header, see
+/// [section-5.1](https://tools.ietf.org/html/rfc7845#section-5.1)
+#[derive(Clone, Debug, PartialEq)]
+pub struct OpusHead {
+    pub version: u8,
+    pub channels: u8,
+    /// Samples to discard at the start of the decoded output
+    pub pre_skip: u16,
+    /// Sample rate of the encoder input, 0 if unknown, for information only
+    pub input_sample_rate: u32,
+    /// Gain to apply to the decoded output, in Q7.8 dB
+    pub output_gain: i16,
+    pub family: u8,
+    pub streams: u8,
+    pub coupled_streams: u8,
+    /// Decoded channel of each output channel, 255 for silence
+    ///
+    /// It is implicit for the family 0 and not used by the family 3.
+    pub mapping: Vec<u8>,
+    /// Demixing matrix of the family 3 in Q15, one column per decoded channel
+    ///
+    /// See [section-3.2](https://tools.ietf.org/html/rfc8486#section-3.2)
+    pub demixing: Vec<i16>,
+}
+
+impl Default for OpusHead {
+    fn default() -> Self {
+        OpusHead {
+            version: 1,
+            channels: 2,
+            pre_skip: 0,
+            input_sample_rate: 48000,
+            output_gain: 0,
+            family: 0,
+            streams: 1,
+            coupled_streams: 1,
+            mapping: vec![0, 1],
+            demixing: Vec::new(),
+        }
+    }
+}
+
+impl OpusHead {
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < OPUS_HEAD_SIZE || !buf.starts_with(b"OpusHead") {
+            return Err(Error::InvalidData);
+        }
+
+        // Only the minor version can change in a compatible way
+        let version = buf[8];
+        if version > 15 {
+            return Err(Error::Unsupported(format!("OpusHead version {}", version)));
+        }
+
+        let channels = buf[9];
+        let family = buf[18];
+        let mut head = OpusHead {
+            version,
+            channels,
+            pre_skip: get_u16l(&buf[10..12]),
+            input_sample_rate: get_u32l(&buf[12..16]),
+            output_gain: get_i16l(&buf[16..18]),
+            family,
+            ..Default::default()
+        };
+
+        let channels = channels as usize;
+        if channels == 0 {
+            return Err(Error::InvalidData);
+        }
+
+        if family == 0 {
+            if channels > 2 {
+                return Err(Error::InvalidData);
+            }
+            head.coupled_streams = channels as u8 - 1;
+            head.mapping.truncate(channels);
+
+            return Ok(head);
+        }
+
+        // See [section-5.1.1](https://tools.ietf.org/html/rfc7845#section-5.1.1)
+        // and [section-3.1](https://tools.ietf.org/html/rfc8486#section-3.1)
+        if (family == 1 && channels > 8)
+            || ((family == 2 || family == 3) && !ambisonic_channels(channels))
+            || buf.len() < OPUS_HEAD_SIZE + 2
+        {
+            return Err(Error::InvalidData);
+        }
+
+        head.streams = buf[OPUS_HEAD_SIZE];
+        head.coupled_streams = buf[OPUS_HEAD_SIZE + 1];
+
+        let decoded = head.streams as usize + head.coupled_streams as usize;
+        if head.streams == 0 || head.coupled_streams > head.streams || decoded > 255 {
+            return Err(Error::InvalidData);
+        }
+
+        let table = &buf[OPUS_HEAD_SIZE + 2..];
+        if family == 3 {
+            let matrix_size = 2 * decoded * channels;
+            if table.len() < matrix_size {
+                return Err(Error::InvalidData);
+            }
+
+            head.mapping.clear();
+            head.demixing = table[..matrix_size].chunks_exact(2).map(get_i16l).collect();
+        } else {
+            if table.len() < channels
+                || table[..channels]
+                    .iter()
+                    .any(|&m| m != 255 && m as usize >= decoded)
+            {
+                return Err(Error::InvalidData);
+            }
+
+            head.mapping = table[..channels].to_vec();
+        }
+
+        Ok(head)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = b"OpusHead".to_vec();
+
+        buf.push(self.version);
+        buf.push(self.channels);
+        buf.extend_from_slice(&self.pre_skip.to_le_bytes());
+        buf.extend_from_slice(&self.input_sample_rate.to_le_bytes());
+        buf.extend_from_slice(&self.output_gain.to_le_bytes());
+        buf.push(self.family);
+
+        if self.family != 0 {
+            buf.push(self.streams);
+            buf.push(self.coupled_streams);
+            if self.family == 3 {
+                for c in self.demixing.iter() {
+                    buf.extend_from_slice(&c.to_le_bytes());
+                }
+            } else {
+                buf.extend_from_slice(&self.mapping);
+            }
+        }
+
+        buf
+    }
+}
+
+/// Comment header, see
+/// [section-5.2](https://tools.ietf.org/html/rfc7845#section-5.2)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OpusTags {
+    pub vendor: String,
+    /// Comments in the `NAME=value` form
+    pub comments: Vec<String>,
+}
+
+impl OpusTags {
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        fn string(buf: &mut &[u8]) -> Result<String> {
+            if buf.len() < 4 {
+                return Err(Error::InvalidData);
+            }
+            let len = get_u32l(buf) as usize;
+            if buf.len() - 4 < len {
+                return Err(Error::InvalidData);
+            }
+            let s = String::from_utf8(buf[4..4 + len].to_vec()).map_err(|_| Error::InvalidData)?;
+            *buf = &buf[4 + len..];
+
+            Ok(s)
+        }
+
+        let mut buf = buf.strip_prefix(b"OpusTags").ok_or(Error::InvalidData)?;
+
+        let vendor = string(&mut buf)?;
+        if buf.len() < 4 {
+            return Err(Error::InvalidData);
+        }
+        let count = get_u32l(buf) as usize;
+        buf = &buf[4..];
+
+        // Every comment takes at least 4 bytes
+        if count > buf.len() / 4 {
+            return Err(Error::InvalidData);
+        }
+
+        let comments = (0..count)
+            .map(|_| string(&mut buf))
+            .collect::<Result<_>>()?;
+
+        Ok(OpusTags { vendor, comments })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        fn string(buf: &mut Vec<u8>, s: &str) {
+            buf.extend_from_slice(&(s.len() as u32).to_le_bytes());
+            buf.extend_from_slice(s.as_bytes());
+        }
+
+        let mut buf = b"OpusTags".to_vec();
+
+        string(&mut buf, &self.vendor);
+        buf.extend_from_slice(&(self.comments.len() as u32).to_le_bytes());
+        for c in self.comments.iter() {
+            string(&mut buf, c);
+        }
+
+        buf
+    }
+
+    /// Values of the comments named `name`, ignoring the case
+    pub fn get<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.comments.iter().filter_map(move |c| {
+            let (n, v) = c.split_at(c.find('=')?);
+            if n.eq_ignore_ascii_case(name) {
+                Some(&v[1..])
+            } else {
+                None
+            }
+        })
+    }
+
+    // Q7.8 dB gains, see
+    // [section-5.2.1](https://tools.ietf.org/html/rfc7845#section-5.2.1)
+    fn gain(&self, name: &str) -> Option<i16> {
+        self.get(name).next()?.parse().ok()
+    }
+
+    /// Gain to normalize the track, in Q7.8 dB on top of the output gain
+    pub fn track_gain(&self) -> Option<i16> {
+        self.gain("R128_TRACK_GAIN")
+    }
+
+    /// Gain to normalize the album, in Q7.8 dB on top of the output gain
+    pub fn album_gain(&self) -> Option<i16> {
+        self.gain("R128_ALBUM_GAIN")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn head() {
+        let mut buf = b"OpusHead".to_vec();
+        buf.extend_from_slice(&[
+            1, 3, 0x38, 0x01, 0x44, 0xac, 0, 0, 0x00, 0x06, 1, 2, 1, 0, 2, 1,
+        ]);
+
+        let head = OpusHead::parse(&buf).unwrap();
+        assert_eq!(head.channels, 3);
+        assert_eq!(head.pre_skip, 312);
+        assert_eq!(head.input_sample_rate, 44100);
+        assert_eq!(head.output_gain, 6 * 256);
+        assert_eq!((head.streams, head.coupled_streams), (2, 1));
+        assert_eq!(head.mapping, [0, 2, 1]);
+        assert_eq!(head.serialize(), buf);
+
+        // Wrong magic, major version, mapping out of range and truncated
+        let invalid = |pos: usize, val: u8| {
+            let mut b = buf.clone();
+            b[pos] = val;
+            OpusHead::parse(&b).is_err()
+        };
+        assert!(invalid(0, b'o'));
+        assert!(invalid(8, 0x10));
+        assert!(invalid(9, 0));
+        assert!(invalid(21, 3));
+        assert!(OpusHead::parse(&buf[..21]).is_err());
+
+        let stereo = OpusHead::default();
+        assert_eq!(OpusHead::parse(&stereo.serialize()).unwrap(), stereo);
+    }
+
+    #[test]
+    fn tags() {
+        let tags = OpusTags {
+            vendor: "test".to_owned(),
+            comments: vec![
+                "TITLE=A=B".to_owned(),
+                "r128_track_gain=-512".to_owned(),
+                "R128_ALBUM_GAIN=loud".to_owned(),
+            ],
+        };
+
+        let buf = tags.serialize();
+        assert_eq!(OpusTags::parse(&buf).unwrap(), tags);
+        assert!(OpusTags::parse(&buf[..buf.len() - 1]).is_err());
+
+        assert_eq!(tags.get("title").collect::<Vec<_>>(), ["A=B"]);
+        assert_eq!(tags.track_gain(), Some(-512));
+        assert_eq!(tags.album_gain(), None);
+    }
+}
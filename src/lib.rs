@@ -26,9 +26,12 @@ extern crate integer_sqrt;
 
 mod entropy;
 mod maths;
-mod packet;
 
 mod celt;
 mod silk;
 
 pub mod decoder;
+pub mod header;
+pub mod ogg;
+pub mod packet;
+pub mod repacketizer;
@@ -0,0 +1,704 @@
+//! Ogg Opus reader and writer
+//!
+//! Extract the Opus streams and their packets from an Ogg file or store
+//! them in one, see
+//! [rfc7845](https://tools.ietf.org/html/rfc7845)
+
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+
+use crate::bitstream::byteread::{get_i64l, get_u32l};
+use crate::bitstream::bytewrite::{put_i64l, put_u32l};
+use crate::codec::error::*;
+use crate::data::packet::Packet as AVPacket;
+
+use crate::header::{OpusHead, OpusTags};
+use crate::packet;
+
+pub(crate) const CAPTURE_PATTERN: &[u8; 4] = b"OggS";
+pub(crate) const PAGE_HEADER_SIZE: usize = 27;
+
+// Flush the pages once they hold this many bytes
+const PAGE_SIZE: usize = 4096;
+
+// Page header type flags, see
+// [section-6](https://tools.ietf.org/html/rfc3533#section-6)
+pub(crate) const CONTINUED: u8 = 0x01;
+pub(crate) const BOS: u8 = 0x02;
+pub(crate) const EOS: u8 = 0x04;
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+
+    while i < 256 {
+        let mut r = (i as u32) << 24;
+        let mut j = 0;
+        while j < 8 {
+            r = if r & 0x8000_0000 != 0 {
+                (r << 1) ^ 0x04c1_1db7
+            } else {
+                r << 1
+            };
+            j += 1;
+        }
+        table[i] = r;
+        i += 1;
+    }
+
+    table
+}
+
+static CRC_TABLE: [u32; 256] = crc_table();
+
+// Page checksum, computed with the checksum field set to zero
+pub(crate) fn crc(crc: u32, buf: &[u8]) -> u32 {
+    buf.iter().fold(crc, |crc, &b| {
+        (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ b) as usize]
+    })
+}
+
+/// Events produced by the [`Reader`]
+#[derive(Debug)]
+pub enum Event {
+    /// An Opus stream starts, the ones after the first are chained
+    NewStream {
+        serial: u32,
+        /// Identification header, serialized it is the decoder extradata
+        head: OpusHead,
+        tags: OpusTags,
+    },
+    /// A packet of the current stream
+    ///
+    /// The packet `pts` and `duration` are in 48kHz samples and include
+    /// the stream pre-skip.
+    NewPacket {
+        packet: AVPacket,
+        /// Granule position at the end of the packet
+        granule: u64,
+        /// Samples at the end of the packet past the end of the stream, to
+        /// be dropped with `Dec::send_packet_discard`
+        discard: usize,
+    },
+    Eof,
+}
+
+struct Page {
+    flags: u8,
+    granule: i64,
+    serial: u32,
+    seq: u32,
+    lacing: Vec<u8>,
+    body: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Head,
+    Tags,
+    Audio,
+}
+
+/// Ogg Opus reader
+///
+/// Streams other than the Opus one are skipped, chained streams are
+/// read one after the other.
+pub struct Reader<R> {
+    r: R,
+    // Serial of the Opus stream being read
+    serial: Option<u32>,
+    state: State,
+    head: Option<OpusHead>,
+    // Sequence number of the next page
+    seq: u32,
+    // Packet continuing on the next page
+    partial: Vec<u8>,
+    // Bytes following a corrupted capture pattern, to search again
+    pending: VecDeque<u8>,
+    // Granule position at the end of the last packet
+    granule: Option<i64>,
+    events: VecDeque<Event>,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(r: R) -> Self {
+        Reader {
+            r,
+            serial: None,
+            state: State::Head,
+            head: None,
+            seq: 0,
+            partial: Vec::new(),
+            pending: VecDeque::new(),
+            granule: None,
+            events: VecDeque::new(),
+        }
+    }
+
+    // Fill `buf` from the pending bytes and then from the input, returning
+    // false and keeping the bytes read as pending at the end of the input
+    fn fill(&mut self, buf: &mut [u8]) -> Result<bool> {
+        let mut n = self.pending.len().min(buf.len());
+        for (b, p) in buf.iter_mut().zip(self.pending.drain(..n)) {
+            *b = p;
+        }
+
+        while n < buf.len() {
+            match self.r.read(&mut buf[n..]) {
+                Ok(0) => {
+                    for &b in buf[..n].iter().rev() {
+                        self.pending.push_front(b);
+                    }
+                    return Ok(false);
+                }
+                Ok(len) => n += len,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => return Err(Error::InvalidData),
+            }
+        }
+
+        Ok(true)
+    }
+
+    // Read the next valid page, see
+    // [section-6](https://tools.ietf.org/html/rfc3533#section-6)
+    fn read_page(&mut self) -> Result<Option<Page>> {
+        let mut hdr = [0u8; PAGE_HEADER_SIZE];
+
+        if !self.fill(&mut hdr)? {
+            return Ok(None);
+        }
+
+        loop {
+            // Resynchronize on the next capture pattern
+            while &hdr[..4] != CAPTURE_PATTERN {
+                hdr.copy_within(1.., 0);
+                if !self.fill(&mut hdr[PAGE_HEADER_SIZE - 1..])? {
+                    return Ok(None);
+                }
+            }
+
+            // A truncated page is as corrupted as one failing the checksum
+            let mut lacing = vec![0; hdr[26] as usize];
+            let mut body = Vec::new();
+            if !self.fill(&mut lacing)? {
+                lacing.clear();
+            } else {
+                body.resize(lacing.iter().map(|&l| l as usize).sum(), 0);
+                if !self.fill(&mut body)? {
+                    body.clear();
+                } else {
+                    let checksum = get_u32l(&hdr[22..26]);
+                    let mut zeroed = hdr;
+                    zeroed[22..26].copy_from_slice(&[0; 4]);
+
+                    if hdr[4] == 0 && crc(crc(crc(0, &zeroed), &lacing), &body) == checksum {
+                        return Ok(Some(Page {
+                            flags: hdr[5],
+                            granule: get_i64l(&hdr[6..14]),
+                            serial: get_u32l(&hdr[14..18]),
+                            seq: get_u32l(&hdr[18..22]),
+                            lacing,
+                            body,
+                        }));
+                    }
+                }
+            }
+
+            // The corrupted page may hide the next ones, search again from
+            // the byte after its capture pattern as `ogg_sync_pageseek` does
+            let rest = std::mem::take(&mut self.pending);
+            self.pending
+                .extend(hdr[1..].iter().chain(&lacing).chain(&body).chain(&rest));
+            if !self.fill(&mut hdr)? {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.serial = None;
+        self.state = State::Head;
+        self.partial.clear();
+        self.granule = None;
+    }
+
+    fn parse_page(&mut self, page: Page) -> Result<()> {
+        match self.serial {
+            Some(serial) if serial == page.serial => {}
+            None if page.flags & BOS != 0 && page.body.starts_with(b"OpusHead") => {
+                self.serial = Some(page.serial);
+                self.seq = page.seq;
+            }
+            _ => return Ok(()),
+        }
+
+        // A page went missing, drop the packet spanning it
+        let mut skip = page.flags & CONTINUED != 0;
+        if page.seq != self.seq || !skip {
+            self.partial.clear();
+        }
+        skip &= self.partial.is_empty();
+        self.seq = page.seq.wrapping_add(1);
+
+        let mut pkts = Vec::new();
+        let mut off = 0;
+        for &l in page.lacing.iter() {
+            let end = off + l as usize;
+            if !skip {
+                self.partial.extend_from_slice(&page.body[off..end]);
+            }
+            off = end;
+
+            if l < 255 {
+                if !skip {
+                    pkts.push(std::mem::take(&mut self.partial));
+                }
+                skip = false;
+            }
+        }
+
+        // The header packets come first
+        let mut audio = &pkts[..];
+        while self.state != State::Audio {
+            let (pkt, rest) = match audio.split_first() {
+                Some(split) => split,
+                None => break,
+            };
+            audio = rest;
+
+            if self.state == State::Head {
+                self.head = Some(OpusHead::parse(pkt)?);
+                self.state = State::Tags;
+            } else {
+                let tags = OpusTags::parse(pkt)?;
+                self.events.push_back(Event::NewStream {
+                    serial: page.serial,
+                    head: self.head.take().ok_or(Error::InvalidData)?,
+                    tags,
+                });
+                self.state = State::Audio;
+            }
+        }
+
+        self.push_audio(&page, audio)?;
+
+        if page.flags & EOS != 0 {
+            self.reset();
+        }
+
+        Ok(())
+    }
+
+    // Timestamp the audio packets completed in the page, see
+    // [section-4](https://tools.ietf.org/html/rfc7845#section-4)
+    fn push_audio(&mut self, page: &Page, pkts: &[Vec<u8>]) -> Result<()> {
+        if pkts.is_empty() {
+            return Ok(());
+        }
+
+        let durations = pkts
+            .iter()
+            .map(|p| packet::samples(p).map(|s| s as i64))
+            .collect::<Result<Vec<_>>>()?;
+        let total = durations.iter().sum::<i64>();
+        let eos = page.flags & EOS != 0;
+
+        if page.granule < 0 {
+            return Err(Error::InvalidData);
+        }
+
+        // The last page can end before its packets, trimming them
+        let mut start = match self.granule {
+            Some(granule) if eos => granule,
+            _ => page.granule - total,
+        };
+        if start < 0 {
+            if !eos || self.granule.is_some() {
+                return Err(Error::InvalidData);
+            }
+            start = 0;
+        }
+
+        for (pkt, &duration) in pkts.iter().zip(durations.iter()) {
+            let end = start + duration;
+            let discard = if eos && end > page.granule {
+                (end - page.granule).min(duration) as usize
+            } else {
+                0
+            };
+
+            let mut packet = AVPacket::new();
+            packet.data = pkt.clone();
+            packet.t.pts = Some(start);
+            packet.t.duration = Some(duration as u64);
+
+            self.events.push_back(Event::NewPacket {
+                packet,
+                granule: end as u64,
+                discard,
+            });
+
+            start = end;
+        }
+
+        self.granule = Some(start);
+
+        Ok(())
+    }
+
+    /// Read the next stream or packet
+    pub fn read_event(&mut self) -> Result<Event> {
+        loop {
+            if let Some(ev) = self.events.pop_front() {
+                return Ok(ev);
+            }
+
+            match self.read_page()? {
+                Some(page) => self.parse_page(page)?,
+                None => return Ok(Event::Eof),
+            }
+        }
+    }
+}
+
+/// Ogg Opus writer
+///
+/// The packets are grouped in pages holding at most one second of audio
+/// by default, call [`Writer::finish`] to end the stream.
+pub struct Writer<W> {
+    w: W,
+    serial: u32,
+    // Sequence number of the next page
+    seq: u32,
+    // Granule position at the end of the last packet
+    granule: u64,
+    // Granule position of the last page written
+    page_granule: u64,
+    max_delay: u64,
+    // Page being filled
+    lacing: Vec<u8>,
+    body: Vec<u8>,
+    // Duration of the packets in the page
+    duration: u64,
+    // Granule position of the last packet completed in the page
+    page_end: Option<u64>,
+    // The page starts with the rest of a packet
+    continued: bool,
+}
+
+impl<W: Write> Writer<W> {
+    /// Start the stream `serial` writing its header pages
+    pub fn new(w: W, serial: u32, head: &OpusHead, tags: &OpusTags) -> Result<Self> {
+        let mut wr = Writer {
+            w,
+            serial,
+            seq: 0,
+            granule: 0,
+            page_granule: 0,
+            max_delay: 48000,
+            lacing: Vec::new(),
+            body: Vec::new(),
+            duration: 0,
+            page_end: None,
+            continued: false,
+        };
+
+        // The headers have their own pages, see
+        // [section-3](https://tools.ietf.org/html/rfc7845#section-3)
+        wr.push(&head.serialize())?;
+        wr.flush(BOS)?;
+        wr.push(&tags.serialize())?;
+        wr.flush(0)?;
+
+        Ok(wr)
+    }
+
+    /// Set the maximum duration of the pages, in 48kHz samples
+    pub fn set_max_delay(&mut self, samples: u64) {
+        self.max_delay = samples;
+    }
+
+    // Write the current page
+    fn flush(&mut self, flags: u8) -> Result<()> {
+        let mut hdr = [0u8; PAGE_HEADER_SIZE];
+        let granule = self.page_end.map_or(-1, |g| g as i64);
+
+        hdr[..4].copy_from_slice(CAPTURE_PATTERN);
+        hdr[5] = flags | if self.continued { CONTINUED } else { 0 };
+        put_i64l(&mut hdr[6..14], granule);
+        put_u32l(&mut hdr[14..18], self.serial);
+        put_u32l(&mut hdr[18..22], self.seq);
+        hdr[26] = self.lacing.len() as u8;
+
+        let checksum = crc(crc(crc(0, &hdr), &self.lacing), &self.body);
+        put_u32l(&mut hdr[22..26], checksum);
+
+        for buf in [&hdr[..], &self.lacing, &self.body].iter() {
+            self.w.write_all(buf).map_err(|_| Error::InvalidData)?;
+        }
+
+        if let Some(granule) = self.page_end {
+            self.page_granule = granule;
+        }
+        self.seq = self.seq.wrapping_add(1);
+        self.lacing.clear();
+        self.body.clear();
+        self.duration = 0;
+        self.page_end = None;
+        self.continued = false;
+
+        Ok(())
+    }
+
+    // Add a packet to the page, continuing it on new pages if needed
+    fn push(&mut self, buf: &[u8]) -> Result<()> {
+        let mut off = 0;
+
+        loop {
+            if self.lacing.len() == 255 {
+                self.flush(0)?;
+                self.continued = off > 0;
+            }
+
+            let len = (buf.len() - off).min(255);
+            self.lacing.push(len as u8);
+            self.body.extend_from_slice(&buf[off..off + len]);
+            off += len;
+
+            if len < 255 {
+                break;
+            }
+        }
+
+        self.page_end = Some(self.granule);
+
+        Ok(())
+    }
+
+    /// Add an Opus packet to the stream
+    pub fn write_packet(&mut self, buf: &[u8]) -> Result<()> {
+        let duration = packet::samples(buf)? as u64;
+
+        if !self.lacing.is_empty()
+            && (self.duration + duration > self.max_delay
+                || self.body.len() + buf.len() > PAGE_SIZE)
+        {
+            self.flush(0)?;
+        }
+
+        self.granule += duration;
+        self.duration += duration;
+
+        self.push(buf)
+    }
+
+    /// End the stream dropping `discard` samples from the end of the last
+    /// packets, see [section-4.4](https://tools.ietf.org/html/rfc7845#section-4.4)
+    pub fn finish(mut self, discard: u64) -> Result<W> {
+        let end = self
+            .granule
+            .checked_sub(discard)
+            .filter(|&end| end >= self.page_granule)
+            .ok_or(Error::InvalidData)?;
+
+        self.page_end = Some(end);
+        self.flush(EOS)?;
+
+        Ok(self.w)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bitstream::bytewrite::{put_u32l, put_u64l};
+
+    fn page(flags: u8, granule: i64, serial: u32, seq: u32, lacing: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut p = b"OggS".to_vec();
+        p.extend_from_slice(&[0, flags]);
+        p.resize(PAGE_HEADER_SIZE, 0);
+        put_u64l(&mut p[6..14], granule as u64);
+        put_u32l(&mut p[14..18], serial);
+        put_u32l(&mut p[18..22], seq);
+        p[26] = lacing.len() as u8;
+        p.extend_from_slice(lacing);
+        p.extend_from_slice(body);
+        let c = crc(0, &p);
+        put_u32l(&mut p[22..26], c);
+        p
+    }
+
+    fn headers(serial: u32) -> Vec<u8> {
+        let mut head = b"OpusHead".to_vec();
+        head.extend_from_slice(&[1, 2, 0x38, 0x01, 0x80, 0xbb, 0, 0, 0, 0, 0]);
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend_from_slice(&[4, 0, 0, 0]);
+        tags.extend_from_slice(b"test");
+        tags.extend_from_slice(&[1, 0, 0, 0, 7, 0, 0, 0]);
+        tags.extend_from_slice(b"A=value");
+
+        [
+            page(BOS, 0, serial, 0, &[19], &head),
+            page(0, 0, serial, 1, &[tags.len() as u8], &tags),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn checksum() {
+        assert_eq!(crc(0, b"123456789"), 0x89a1_897f);
+    }
+
+    #[test]
+    fn read() {
+        // 20ms CELT packets, one spanning two pages
+        let long = [&[0xf8][..], &[0xaa; 299][..]].concat();
+        let mut data = headers(1);
+
+        // A foreign stream, skipped
+        data.extend(page(BOS, 0, 7, 0, &[4], b"fish"));
+        data.extend(page(
+            0,
+            1960,
+            1,
+            2,
+            &[3, 3, 255],
+            &[&[0xf8, 1, 2][..], &[0xf8, 3, 4], &long[..255]].concat(),
+        ));
+        // The last page ends 100 samples before its packets
+        data.extend(page(CONTINUED | EOS, 2820, 1, 3, &[45], &long[255..]));
+        // Chained stream, shorter than its only packet
+        data.extend(headers(2));
+        data.extend(page(EOS, 500, 2, 2, &[1], &[0xf8]));
+
+        let mut r = Reader::new(&data[..]);
+
+        let stream = |r: &mut Reader<&[u8]>, s| match r.read_event().unwrap() {
+            Event::NewStream { serial, head, tags } => {
+                assert_eq!(serial, s);
+                assert_eq!(head.pre_skip, 312);
+                assert_eq!(tags.vendor, "test");
+                assert_eq!(tags.comments, ["A=value"]);
+            }
+            ev => panic!("{:?}", ev),
+        };
+        let packet =
+            |r: &mut Reader<&[u8]>, pts, end, trim, buf: &[u8]| match r.read_event().unwrap() {
+                Event::NewPacket {
+                    packet,
+                    granule,
+                    discard,
+                } => {
+                    assert_eq!(packet.t.pts, Some(pts));
+                    assert_eq!(granule, end);
+                    assert_eq!(discard, trim);
+                    assert_eq!(packet.data, buf);
+                }
+                ev => panic!("{:?}", ev),
+            };
+
+        // The first stream starts at sample 40
+        stream(&mut r, 1);
+        packet(&mut r, 40, 1000, 0, &[0xf8, 1, 2]);
+        packet(&mut r, 1000, 1960, 0, &[0xf8, 3, 4]);
+        packet(&mut r, 1960, 2920, 100, &long);
+
+        stream(&mut r, 2);
+        packet(&mut r, 0, 960, 460, &[0xf8]);
+
+        assert!(matches!(r.read_event(), Ok(Event::Eof)));
+    }
+
+    #[test]
+    fn resync() {
+        let mut data = headers(1);
+
+        // The corrupted lacing spans the next page
+        let mut bad = page(0, 1000, 1, 2, &[3], &[0xf8, 1, 2]);
+        bad[PAGE_HEADER_SIZE] = 255;
+        data.extend(bad);
+        data.extend(page(EOS, 1960, 1, 3, &[3], &[0xf8, 3, 4]));
+
+        let mut r = Reader::new(&data[..]);
+        assert!(matches!(
+            r.read_event(),
+            Ok(Event::NewStream { serial: 1, .. })
+        ));
+        match r.read_event().unwrap() {
+            Event::NewPacket {
+                packet, granule, ..
+            } => {
+                assert_eq!(packet.t.pts, Some(1000));
+                assert_eq!(granule, 1960);
+                assert_eq!(packet.data, [0xf8, 3, 4]);
+            }
+            ev => panic!("{:?}", ev),
+        }
+        assert!(matches!(r.read_event(), Ok(Event::Eof)));
+    }
+
+    #[test]
+    fn write() {
+        let head = OpusHead {
+            pre_skip: 312,
+            ..Default::default()
+        };
+        let tags = OpusTags {
+            vendor: "test".to_owned(),
+            comments: vec!["A=value".to_owned()],
+        };
+
+        // 20ms CELT packets, the last one spanning two pages
+        let mut pkts = vec![vec![0xf8, 0xaa]; 253];
+        pkts.push([&[0xf8][..], &[0xbb; 999][..]].concat());
+
+        let mut w = Writer::new(Vec::new(), 5, &head, &tags).unwrap();
+        w.set_max_delay(u64::MAX);
+        for pkt in pkts.iter() {
+            w.write_packet(pkt).unwrap();
+        }
+        assert!(w.write_packet(&[]).is_err());
+        let data = w.finish(100).unwrap();
+
+        // Headers, the first packets with the start of the last one and its end
+        let mut off = 0;
+        let mut granules = Vec::new();
+        while off < data.len() {
+            let segments = data[off + 26] as usize;
+            let lacing = &data[off + PAGE_HEADER_SIZE..off + PAGE_HEADER_SIZE + segments];
+            granules.push(get_i64l(&data[off + 6..]));
+            off += PAGE_HEADER_SIZE + segments + lacing.iter().map(|&l| l as usize).sum::<usize>();
+        }
+        assert_eq!(granules, [0, 0, 253 * 960, 254 * 960 - 100]);
+
+        let mut r = Reader::new(&data[..]);
+        match r.read_event().unwrap() {
+            Event::NewStream {
+                serial,
+                head: h,
+                tags: t,
+            } => {
+                assert_eq!(serial, 5);
+                assert_eq!(h, head);
+                assert_eq!(t, tags);
+            }
+            ev => panic!("{:?}", ev),
+        }
+        for (i, pkt) in pkts.iter().enumerate() {
+            match r.read_event().unwrap() {
+                Event::NewPacket {
+                    packet,
+                    granule,
+                    discard,
+                } => {
+                    assert_eq!(&packet.data, pkt);
+                    assert_eq!(granule, (i as u64 + 1) * 960);
+                    assert_eq!(discard, if i == 253 { 100 } else { 0 });
+                }
+                ev => panic!("{:?}", ev),
+            }
+        }
+        assert!(matches!(r.read_event(), Ok(Event::Eof)));
+    }
+}
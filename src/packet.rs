@@ -1,15 +1,28 @@
 //! Opus Packet parsing
+//!
+//! Inspect the packets without decoding them, e.g. to compute their
+//! timestamps.
 
 use crate::codec::error::*;
 
+/// Frame count and framing
+///
+/// See [section-3.2](https://tools.ietf.org/html/rfc6716#section-3.2)
 #[derive(Debug, PartialEq, Clone)]
 pub enum Code {
+    /// One frame
     Single,
+    /// Two frames of equal size
     DoubleEqual,
+    /// Two frames of different size
     DoubleVary,
+    /// An arbitrary number of frames
     Multiple,
 }
 
+/// Coding mode
+///
+/// See [section-3.1](https://tools.ietf.org/html/rfc6716#section-3.1)
 #[derive(Debug, PartialEq, Clone)]
 pub enum Mode {
     SILK,
@@ -30,7 +43,7 @@ pub enum Bandwidth {
 }
 
 impl Bandwidth {
-    pub fn celt_band(&self) -> usize {
+    pub(crate) fn celt_band(&self) -> usize {
         use self::Bandwidth::*;
         match self {
             Narrow => 13,
@@ -61,6 +74,9 @@ pub enum FrameDuration {
     VeryLong = 2880,
 }
 
+/// Parsed packet, the frames point to the parsed buffer
+///
+/// See [section-3](https://tools.ietf.org/html/rfc6716#section-3)
 #[derive(Debug, PartialEq, Clone)]
 pub struct Packet<'a> {
     code: Code,
@@ -75,7 +91,7 @@ pub struct Packet<'a> {
 }
 
 fn xiph_lacing_u16(buf: &[u8]) -> Result<(usize, usize)> {
-    let mut v = buf[0] as usize;
+    let mut v = *buf.first().ok_or(Error::InvalidData)? as usize;
     if v >= 252 {
         if buf.len() > 1 {
             v += 4 * buf[1] as usize;
@@ -98,7 +114,7 @@ fn xiph_lacing_u32(buf: &[u8]) -> Result<(usize, usize)> {
         v += b;
         o += 1;
         if b < 255 {
-            break;
+            return Ok((o, v as usize));
         } else {
             v -= 1;
         }
@@ -107,99 +123,195 @@ fn xiph_lacing_u32(buf: &[u8]) -> Result<(usize, usize)> {
             return Err(Error::InvalidData);
         }
     }
-    Ok((o, v as usize))
+
+    // The buffer ended before the last byte of the length
+    Err(Error::InvalidData)
 }
 
 const MAX_FRAME_SIZE: usize = 1275;
 const MAX_FRAMES: usize = 48;
-const MAX_PACKET_DUR: usize = 5760;
+pub(crate) const MAX_PACKET_DUR: usize = 5760;
 
 impl<'a> Packet<'a> {
-    fn single_packet(&mut self, buf: &'a [u8]) -> Result<()> {
+    // The self-delimited packets store the length of the last frame, see
+    // [appendix-B](https://tools.ietf.org/html/rfc6716#appendix-B)
+    fn frame_len(buf: &[u8], self_delimited: bool) -> Result<(usize, usize)> {
+        if self_delimited {
+            xiph_lacing_u16(buf)
+        } else {
+            Ok((0, buf.len()))
+        }
+    }
+
+    fn single_packet(&mut self, buf: &'a [u8], self_delimited: bool) -> Result<usize> {
         self.code = Code::Single;
         self.vbr = false;
-        self.frames.push(buf);
-        Ok(())
+
+        let (off, len) = Self::frame_len(buf, self_delimited)?;
+        let frame = buf.get(off..off + len).ok_or(Error::InvalidData)?;
+
+        if len > MAX_FRAME_SIZE {
+            return Err(Error::InvalidData);
+        }
+
+        self.frames.push(frame);
+        Ok(off + len)
     }
 
-    fn double_packet_es(&mut self, buf: &'a [u8]) -> Result<()> {
+    fn double_packet_es(&mut self, buf: &'a [u8], self_delimited: bool) -> Result<usize> {
         self.code = Code::DoubleEqual;
         self.vbr = false;
 
-        if buf.len() & 1 != 0 {
+        let (off, len) = Self::frame_len(buf, self_delimited)?;
+        let len = if self_delimited { len * 2 } else { len };
+        let buf = buf.get(off..off + len).ok_or(Error::InvalidData)?;
+
+        if len & 1 != 0 || len / 2 > MAX_FRAME_SIZE {
             return Err(Error::InvalidData);
         }
 
-        for b in buf.chunks(buf.len()) {
-            self.frames.push(b);
-        }
-        Ok(())
+        let (b1, b2) = buf.split_at(len / 2);
+
+        self.frames.push(b1);
+        self.frames.push(b2);
+        Ok(off + len)
     }
 
-    fn double_packet_va(&mut self, buf: &'a [u8]) -> Result<()> {
+    fn double_packet_va(&mut self, buf: &'a [u8], self_delimited: bool) -> Result<usize> {
         self.code = Code::DoubleVary;
         self.vbr = true;
 
         let (off, len) = xiph_lacing_u16(buf)?;
-        if len + off > buf.len() {
+        let (off2, len2) = Self::frame_len(&buf[off..], self_delimited)?;
+        let off = off + off2;
+        let len2 = if self_delimited {
+            len2
+        } else {
+            len2.checked_sub(len).ok_or(Error::InvalidData)?
+        };
+
+        if len2 > MAX_FRAME_SIZE {
             return Err(Error::InvalidData);
         }
 
-        let (b1, b2) = buf[off..].split_at(len);
+        let b1 = buf.get(off..off + len).ok_or(Error::InvalidData)?;
+        let b2 = buf
+            .get(off + len..off + len + len2)
+            .ok_or(Error::InvalidData)?;
 
         self.frames.push(b1);
         self.frames.push(b2);
-        Ok(())
+        Ok(off + len + len2)
     }
 
-    fn multiple_packet(&mut self, buf: &'a [u8]) -> Result<()> {
+    fn multiple_packet(&mut self, buf: &'a [u8], self_delimited: bool) -> Result<usize> {
         self.code = Code::Multiple;
-        self.vbr = (buf[0] >> 7) & 0x01 == 1;
 
-        let count = (buf[0] & 0x3f) as usize;
-        let padding = (buf[0] >> 6) & 0x01 == 1;
+        let ch = *buf.first().ok_or(Error::InvalidData)?;
+        self.vbr = (ch >> 7) & 0x01 == 1;
+
+        let count = (ch & 0x3f) as usize;
+        let padding = (ch >> 6) & 0x01 == 1;
 
         if count == 0 || count > MAX_FRAMES {
             return Err(Error::InvalidData);
         }
 
-        let buf = if padding {
-            let (off, pad) = xiph_lacing_u32(&buf[1..])?;
+        let mut off = 1;
+        if padding {
+            let (o, pad) = xiph_lacing_u32(&buf[off..])?;
+            off += o;
             self.padding = pad;
-            &buf[1 + off..buf.len() - pad]
-        } else {
-            &buf[1..]
-        };
+        }
 
+        let mut lens = Vec::with_capacity(count);
         if self.vbr {
-            let mut b = buf;
-            println!("count {} padding {}", count, self.padding);
-            let mut lens = Vec::with_capacity(count - 1);
-            for i in 0..count - 1 {
-                let (off, len) = xiph_lacing_u16(b)?;
-                println!("packet {} {}", i, len);
-                b = &b[off..];
+            for _ in 0..count - 1 {
+                let (o, len) = xiph_lacing_u16(&buf[off..])?;
+                off += o;
                 lens.push(len);
             }
-            for len in lens.iter() {
-                let (b1, rem) = b.split_at(*len);
-                self.frames.push(b1);
-                b = rem;
+        }
+
+        // The length of the last frame if vbr, the one of every frame otherwise
+        let (o, len) = Self::frame_len(&buf[off..], self_delimited)?;
+        off += o;
+
+        if self_delimited {
+            if self.vbr {
+                lens.push(len);
+            } else {
+                lens.resize(count, len);
             }
         } else {
-            let len = buf.len() / count;
-            if len * count != buf.len() || len > MAX_FRAME_SIZE {
-                return Err(Error::InvalidData);
+            let len = len.checked_sub(self.padding).ok_or(Error::InvalidData)?;
+            if self.vbr {
+                let last = lens
+                    .iter()
+                    .try_fold(len, |rem, &l| rem.checked_sub(l))
+                    .ok_or(Error::InvalidData)?;
+                lens.push(last);
+            } else {
+                if len % count != 0 {
+                    return Err(Error::InvalidData);
+                }
+                lens.resize(count, len / count);
             }
+        }
 
-            for b in buf.chunks(len) {
-                self.frames.push(b);
+        for &len in lens.iter() {
+            if len > MAX_FRAME_SIZE {
+                return Err(Error::InvalidData);
             }
+            let frame = buf.get(off..off + len).ok_or(Error::InvalidData)?;
+            self.frames.push(frame);
+            off += len;
+        }
+
+        if off + self.padding > buf.len() {
+            return Err(Error::InvalidData);
         }
-        Ok(())
+
+        Ok(off + self.padding)
     }
 
+    /// Parse a packet using the whole `buf`
     pub fn from_slice(buf: &'a [u8]) -> Result<Self> {
+        Self::parse(buf, false).map(|(p, _)| p)
+    }
+
+    /// Parse the self-delimited packet at the start of `buf`
+    ///
+    /// Return the packet and the number of bytes it uses, see
+    /// [appendix-B](https://tools.ietf.org/html/rfc6716#appendix-B)
+    pub fn from_slice_self_delimited(buf: &'a [u8]) -> Result<(Self, usize)> {
+        Self::parse(buf, true)
+    }
+
+    /// Configuration number, the upper 5 bits of the TOC byte
+    pub fn config(&self) -> usize {
+        self.config
+    }
+
+    pub fn code(&self) -> Code {
+        self.code.clone()
+    }
+
+    /// Whether the frames have different sizes
+    pub fn vbr(&self) -> bool {
+        self.vbr
+    }
+
+    pub fn channels(&self) -> usize {
+        self.stereo as usize + 1
+    }
+
+    /// Number of samples at 48kHz
+    pub fn samples(&self) -> usize {
+        self.frames.len() * self.frame_duration as usize
+    }
+
+    fn parse(buf: &'a [u8], self_delimited: bool) -> Result<(Self, usize)> {
         let mut p = Packet {
             code: Code::Single,
             stereo: false,
@@ -212,38 +324,21 @@ impl<'a> Packet<'a> {
             frames: Vec::new(),
         };
 
-        if buf.len() < 1 {
-            unimplemented!();
-        }
-
-        let code = buf[0] & 0x3;
-        let config = (buf[0] >> 3) & 0x1f;
-        p.stereo = (buf[0] >> 2) & 0x01 == 1;
-
-        if code >= 2 && buf.len() < 1 {
-            unimplemented!();
-        }
+        let toc = *buf.first().ok_or(Error::InvalidData)?;
+        let code = toc & 0x3;
+        let config = (toc >> 3) & 0x1f;
+        p.config = config as usize;
+        p.stereo = (toc >> 2) & 0x01 == 1;
 
         let buf = &buf[1..];
 
-        println!("code {} config {}", code, config);
-
-        // TODO support self delimited
-        match code {
-            0 => {
-                p.single_packet(&buf)?;
-            }
-            1 => {
-                p.double_packet_es(&buf)?;
-            }
-            2 => {
-                p.double_packet_va(&buf)?;
-            }
-            3 => {
-                p.multiple_packet(&buf)?;
-            }
-            _ => unimplemented!(),
-        }
+        let used = 1 + match code {
+            0 => p.single_packet(buf, self_delimited)?,
+            1 => p.double_packet_es(buf, self_delimited)?,
+            2 => p.double_packet_va(buf, self_delimited)?,
+            3 => p.multiple_packet(buf, self_delimited)?,
+            _ => unreachable!(),
+        };
 
         match config {
             c @ 0..=11 => {
@@ -313,6 +408,127 @@ impl<'a> Packet<'a> {
             _ => unreachable!(),
         }
 
-        Ok(p)
+        // See [section-3.4](https://tools.ietf.org/html/rfc6716#section-3.4) R5
+        if p.samples() > MAX_PACKET_DUR {
+            return Err(Error::InvalidData);
+        }
+
+        Ok((p, used))
+    }
+}
+
+/// Bandwidth of the packet in `buf`
+pub fn bandwidth(buf: &[u8]) -> Result<Bandwidth> {
+    Packet::from_slice(buf).map(|p| p.bandwidth)
+}
+
+/// Number of frames in the packet in `buf`
+pub fn frames(buf: &[u8]) -> Result<usize> {
+    Packet::from_slice(buf).map(|p| p.frames.len())
+}
+
+/// Number of samples at 48kHz in the packet in `buf`
+pub fn samples(buf: &[u8]) -> Result<usize> {
+    Packet::from_slice(buf).map(|p| p.samples())
+}
+
+/// Number of channels coded in the packet in `buf`
+pub fn channels(buf: &[u8]) -> Result<usize> {
+    Packet::from_slice(buf).map(|p| p.channels())
+}
+
+/// Split a multistream packet in the packets of its `streams` streams
+///
+/// All the packets but the last one use the self-delimiting framing.
+pub(crate) fn split_multistream(buf: &[u8], streams: usize) -> Result<Vec<Packet<'_>>> {
+    let mut pkts = Vec::with_capacity(streams);
+    let mut buf = buf;
+
+    for _ in 1..streams {
+        let (pkt, used) = Packet::from_slice_self_delimited(buf)?;
+        pkts.push(pkt);
+        buf = &buf[used..];
+    }
+
+    pkts.push(Packet::from_slice(buf)?);
+
+    Ok(pkts)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn inspect() {
+        // SILK narrowband, 2 frames of 20ms
+        let buf = [0x0b, 0x02, 0xaa, 0xbb];
+        assert_eq!(bandwidth(&buf).unwrap(), Bandwidth::Narrow);
+        assert_eq!(frames(&buf).unwrap(), 2);
+        assert_eq!(samples(&buf).unwrap(), 1920);
+        assert_eq!(channels(&buf).unwrap(), 1);
+
+        // CELT fullband stereo, 2 frames of 20ms of the same size
+        let p = Packet::from_slice(&[0xfd, 0xaa, 0xbb]).unwrap();
+        assert_eq!(p.config(), 31);
+        assert_eq!(p.code(), Code::DoubleEqual);
+        assert_eq!(p.mode, Mode::CELT);
+        assert_eq!(p.bandwidth, Bandwidth::Full);
+        assert_eq!(p.channels(), 2);
+        assert_eq!(p.samples(), 1920);
+
+        assert!(samples(&[]).is_err());
+    }
+
+    #[test]
+    fn malformed_packets() {
+        let long = [0u8; 1277];
+        let bad: &[&[u8]] = &[
+            &[],                             // R1, no toc
+            &long[..],                       // R2, frame longer than 1275 bytes
+            &[0x01, 0xaa],                   // R3, odd frames size
+            &[0x02],                         // R4, no length
+            &[0x02, 0xfc],                   // R4, truncated length
+            &[0x02, 3, 0xaa],                // R4, first frame past the end
+            &[0x03],                         // R5, no frame count
+            &[0x03, 0x00],                   // R5, no frames
+            &[0x1b, 0x03, 0xaa, 0xbb, 0xcc], // R5, 180ms
+            &[0x03, 0x02, 0xaa],             // R6, size not a multiple of the count
+            &[0x03, 0x41, 4, 0xaa],          // R6, padding past the end
+            &[0x03, 0x41, 0xff],             // R6, truncated padding length
+            &[0x03, 0x82],                   // R7, no length
+            &[0x03, 0x82, 3, 0xaa],          // R7, frame past the end
+            &[0x03, 0xc2, 1, 1, 0xaa],       // R7, frames and padding past the end
+        ];
+
+        for buf in bad {
+            let res = Packet::from_slice(buf);
+            assert!(matches!(res, Err(Error::InvalidData)), "{:?}", buf);
+        }
+
+        // No combination of two bytes panics
+        for i in 0..=0xffffu16 {
+            let _ = Packet::from_slice(&i.to_be_bytes());
+            let _ = Packet::from_slice_self_delimited(&i.to_be_bytes());
+        }
+    }
+
+    #[test]
+    fn split_multistream_packet() {
+        let buf = [
+            &[0x00, 2, 0xaa, 0xbb][..],                         // code 0
+            &[0x03, 0xc2, 2, 1, 2, 0xcc, 0xdd, 0xee, 0, 0][..], // code 3, vbr, padded
+            &[0x01, 0xff, 0xff][..],                            // code 1
+        ]
+        .concat();
+
+        let pkts = split_multistream(&buf, 3).unwrap();
+
+        assert_eq!(pkts[0].frames, [&[0xaa, 0xbb][..]]);
+        assert_eq!(pkts[1].frames, [&[0xcc][..], &[0xdd, 0xee][..]]);
+        assert_eq!(pkts[1].padding, 2);
+        assert_eq!(pkts[2].frames.concat(), [0xff, 0xff]);
+
+        assert!(split_multistream(&buf[..6], 3).is_err());
     }
 }
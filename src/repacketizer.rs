@@ -0,0 +1,326 @@
+//! Opus packets repacketization
+//!
+//! Merge the frames of several packets in one or split them in multiple
+//! packets without decoding them.
+
+use std::ops::Range;
+
+use crate::codec::error::*;
+use crate::packet::*;
+
+/// Collect the frames of packets sharing the same configuration
+///
+/// The output packets use the most compact framing for their frames, see
+/// [section-3.2](https://tools.ietf.org/html/rfc6716#section-3.2)
+#[derive(Debug, Default)]
+pub struct Repacketizer<'a> {
+    // Configuration and stereo flag of the packets, as in the TOC byte
+    toc: u8,
+    frame_duration: usize,
+    frames: Vec<&'a [u8]>,
+}
+
+// Code a frame length, see
+// [section-3.2.1](https://tools.ietf.org/html/rfc6716#section-3.2.1)
+fn put_lacing(out: &mut Vec<u8>, len: usize) {
+    if len < 252 {
+        out.push(len as u8);
+    } else {
+        let b = 252 + (len & 0x3);
+        out.push(b as u8);
+        out.push(((len - b) >> 2) as u8);
+    }
+}
+
+fn lacing_len(len: usize) -> usize {
+    if len < 252 {
+        1
+    } else {
+        2
+    }
+}
+
+impl<'a> Repacketizer<'a> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Drop all the frames collected
+    pub fn reset(&mut self) {
+        self.frames.clear();
+    }
+
+    /// Number of frames collected
+    pub fn frames(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Add the frames of the packet in `buf`
+    ///
+    /// The packet must have the same configuration and channels of the ones
+    /// already added and the total duration must not exceed 120ms.
+    pub fn push(&mut self, buf: &'a [u8]) -> Result<()> {
+        let pkt = Packet::from_slice(buf)?;
+
+        self.push_packet(&pkt)
+    }
+
+    fn push_packet(&mut self, pkt: &Packet<'a>) -> Result<()> {
+        let toc = (pkt.config() << 3) as u8 | (pkt.stereo as u8) << 2;
+
+        if self.frames.is_empty() {
+            self.toc = toc;
+            self.frame_duration = pkt.frame_duration as usize;
+        } else if toc != self.toc {
+            return Err(Error::InvalidData);
+        }
+
+        if (self.frames.len() + pkt.frames.len()) * self.frame_duration > MAX_PACKET_DUR {
+            return Err(Error::InvalidData);
+        }
+
+        self.frames.extend_from_slice(&pkt.frames);
+
+        Ok(())
+    }
+
+    // Pack the frames in `range`, padding the packet to `size` bytes if set
+    fn pack(
+        &self,
+        range: Range<usize>,
+        self_delimited: bool,
+        size: Option<usize>,
+    ) -> Result<Vec<u8>> {
+        let frames = self.frames.get(range).ok_or(Error::InvalidData)?;
+        let count = frames.len();
+        let last = frames.last().ok_or(Error::InvalidData)?.len();
+        let toc = self.toc;
+
+        let data = frames.iter().map(|f| f.len()).sum::<usize>();
+        let delimiter = if self_delimited { lacing_len(last) } else { 0 };
+
+        // Codes 0 to 2 have no room for the padding
+        let (code, header) = match frames {
+            [_] => (0, 1),
+            [f0, f1] if f0.len() == f1.len() => (1, 1),
+            [f0, _] => (2, 1 + lacing_len(f0.len())),
+            _ => (3, 0),
+        };
+        let total = header + delimiter + data;
+
+        let mut out = Vec::with_capacity(size.unwrap_or(total + 2 + 2 * count));
+
+        let fits = match size {
+            Some(size) if total > size => return Err(Error::InvalidData),
+            Some(size) => total == size,
+            None => true,
+        };
+
+        if code < 3 && fits {
+            out.push(toc | code);
+            if code == 2 {
+                put_lacing(&mut out, frames[0].len());
+            }
+        } else {
+            let vbr = frames.iter().any(|f| f.len() != last);
+            let lens = &frames[..count - 1];
+            let lacing = if vbr {
+                lens.iter().map(|f| lacing_len(f.len())).sum()
+            } else {
+                0
+            };
+            let total = 2 + lacing + delimiter + data;
+
+            out.push(toc | 0x3);
+            out.push(count as u8 | if vbr { 0x80 } else { 0 });
+
+            if let Some(size) = size {
+                let padding = size.checked_sub(total).ok_or(Error::InvalidData)?;
+
+                // The padding length bytes count as padding as well
+                if padding > 0 {
+                    let n = (padding - 1) / 255;
+                    out[1] |= 0x40;
+                    out.resize(out.len() + n, 255);
+                    out.push((padding - 255 * n - 1) as u8);
+                }
+            }
+
+            if vbr {
+                for f in lens {
+                    put_lacing(&mut out, f.len());
+                }
+            }
+        }
+
+        if self_delimited {
+            put_lacing(&mut out, last);
+        }
+
+        for f in frames {
+            out.extend_from_slice(f);
+        }
+
+        if let Some(size) = size {
+            out.resize(size, 0);
+        }
+
+        Ok(out)
+    }
+
+    /// Pack the frames in `range` in a single packet
+    pub fn out_range(&self, range: Range<usize>) -> Result<Vec<u8>> {
+        self.pack(range, false, None)
+    }
+
+    /// Pack all the frames in a single packet
+    pub fn out(&self) -> Result<Vec<u8>> {
+        self.out_range(0..self.frames.len())
+    }
+}
+
+/// Split the packet in `buf` in packets of a single frame
+pub fn split(buf: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut rp = Repacketizer::new();
+
+    rp.push(buf)?;
+
+    (0..rp.frames()).map(|i| rp.out_range(i..i + 1)).collect()
+}
+
+/// Pad the packet in `buf` to `size` bytes
+pub fn pad(buf: &[u8], size: usize) -> Result<Vec<u8>> {
+    if size < buf.len() {
+        return Err(Error::InvalidData);
+    }
+
+    if size == buf.len() {
+        return Ok(buf.to_vec());
+    }
+
+    let mut rp = Repacketizer::new();
+
+    rp.push(buf)?;
+
+    rp.pack(0..rp.frames(), false, Some(size))
+}
+
+/// Remove the padding from the packet in `buf`
+pub fn unpad(buf: &[u8]) -> Result<Vec<u8>> {
+    let mut rp = Repacketizer::new();
+
+    rp.push(buf)?;
+
+    rp.out()
+}
+
+/// Pad the multistream packet of `streams` streams in `buf` to `size` bytes
+///
+/// The padding goes in the last stream, see
+/// [section-3](https://tools.ietf.org/html/rfc7845#section-3)
+pub fn pad_multistream(buf: &[u8], size: usize, streams: usize) -> Result<Vec<u8>> {
+    if size < buf.len() {
+        return Err(Error::InvalidData);
+    }
+
+    let mut off = 0;
+    for _ in 1..streams {
+        let (_, used) = Packet::from_slice_self_delimited(&buf[off..])?;
+        off += used;
+    }
+
+    let mut out = buf[..off].to_vec();
+    out.extend(pad(&buf[off..], size - off)?);
+
+    Ok(out)
+}
+
+/// Remove the padding from all the streams of the multistream packet in `buf`
+pub fn unpad_multistream(buf: &[u8], streams: usize) -> Result<Vec<u8>> {
+    let pkts = split_multistream(buf, streams)?;
+    let mut out = Vec::with_capacity(buf.len());
+
+    for (i, pkt) in pkts.iter().enumerate() {
+        let mut rp = Repacketizer::new();
+
+        rp.push_packet(pkt)?;
+        out.extend(rp.pack(0..rp.frames(), i + 1 < streams, None)?);
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pad_and_unpad() {
+        let buf = [0xf8, 0xaa, 0xbb];
+
+        assert_eq!(pad(&buf, 3).unwrap(), buf);
+        assert!(pad(&buf, 2).is_err());
+
+        // Code 3 with the padding length and two bytes of padding
+        let padded = pad(&buf, 7).unwrap();
+        assert_eq!(padded, [0xfb, 0x41, 2, 0xaa, 0xbb, 0, 0]);
+        assert_eq!(unpad(&padded).unwrap(), buf);
+
+        // Padding lengths spanning more bytes
+        let padded = pad(&buf, 300).unwrap();
+        assert_eq!(padded.len(), 300);
+        assert_eq!(&padded[..5], &[0xfb, 0x41, 255, 40, 0xaa]);
+        assert_eq!(Packet::from_slice(&padded).unwrap().padding, 294);
+        assert_eq!(unpad(&padded).unwrap(), buf);
+
+        // Code 2 turned into a padded VBR code 3
+        let buf = [0xfa, 2, 0xcc, 0xdd, 0xee];
+        let padded = pad(&buf, 8).unwrap();
+        assert_eq!(padded, [0xfb, 0xc2, 1, 2, 0xcc, 0xdd, 0xee, 0]);
+        assert_eq!(unpad(&padded).unwrap(), buf);
+
+        // Only the last stream gets padded, all of them are unpadded
+        let ms = [
+            &[0xfb, 0x41, 2, 2, 0xcc, 0xdd, 0, 0][..],
+            &[0xf8, 0xaa, 0xbb][..],
+        ]
+        .concat();
+        let padded = pad_multistream(&ms, 20, 2).unwrap();
+        assert_eq!(padded.len(), 20);
+        assert_eq!(&padded[..8], &ms[..8]);
+        assert_eq!(
+            unpad_multistream(&padded, 2).unwrap(),
+            [0xf8, 2, 0xcc, 0xdd, 0xf8, 0xaa, 0xbb]
+        );
+    }
+
+    #[test]
+    fn merge_and_split() {
+        // Three 20ms CELT packets, two of them of the same size
+        let pkts = [
+            &[0xf8, 0xaa, 0xbb][..],
+            &[0xf8, 0xcc, 0xdd][..],
+            &[0xf8, 0xee][..],
+        ];
+
+        let mut rp = Repacketizer::new();
+        rp.push(pkts[0]).unwrap();
+        rp.push(pkts[1]).unwrap();
+        assert_eq!(rp.out().unwrap(), [0xf9, 0xaa, 0xbb, 0xcc, 0xdd]);
+
+        rp.push(pkts[2]).unwrap();
+        let merged = rp.out().unwrap();
+        assert_eq!(merged, [0xfb, 0x83, 2, 2, 0xaa, 0xbb, 0xcc, 0xdd, 0xee]);
+        assert_eq!(rp.out_range(1..3).unwrap(), [0xfa, 2, 0xcc, 0xdd, 0xee]);
+
+        assert_eq!(split(&merged).unwrap(), pkts);
+
+        // Different configuration
+        assert!(rp.push(&[0xf0, 0xaa]).is_err());
+
+        // Longer than 120ms
+        rp.reset();
+        rp.push(&[0xfb, 0x06, 0, 0, 0, 0, 0, 0]).unwrap();
+        assert!(rp.push(&[0xf8, 0xaa]).is_err());
+    }
+}
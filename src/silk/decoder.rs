@@ -9,6 +9,7 @@ use crate::entropy::*;
 use crate::maths::*;
 use crate::packet::*;
 
+use crate::silk::resampler::Resampler;
 use crate::silk::tables::*;
 
 use std::ops::Range;
@@ -40,6 +41,8 @@ pub struct Silk {
     // Todo use directly an AudioQueue ?
     pub left_outbuf: Vec<f32>,
     pub right_outbuf: Vec<f32>,
+
+    resampler: [Resampler; 2],
 }
 
 #[derive(Debug, Default)]
@@ -1292,7 +1295,63 @@ const QUANT_OFFSET: &[&[i32]] = &[
     &[8, 25],  // Voiced
 ];
 
-#[derive(Debug, Default)]
+const MAX_FRAME_SIZE: usize = 320;
+
+// Packet loss concealment parameters, the same as libopus
+const PLC_BWE: f32 = 0.99;
+const PLC_HARM_ATT: [f32; 2] = [32440.0 / 32768.0, 31130.0 / 32768.0];
+const PLC_RAND_ATT_VOICED: [f32; 2] = [31130.0 / 32768.0, 26214.0 / 32768.0];
+const PLC_RAND_ATT_UNVOICED: [f32; 2] = [32440.0 / 32768.0, 29491.0 / 32768.0];
+const PLC_LTP_GAIN_MIN: f32 = 11469.0 / 16384.0;
+const PLC_LTP_GAIN_MAX: f32 = 15565.0 / 16384.0;
+const PLC_PITCH_DRIFT: f32 = 0.01;
+const PLC_MAX_PITCH_LAG_MS: usize = 18;
+const PLC_RAND_BUF_SIZE: usize = 128;
+
+// State of the concealment, taken from the last decoded frame
+#[derive(Debug, Default, Clone)]
+struct Plc {
+    pitch_lag: f32,
+    ltp_taps: [f32; LTP_ORDER],
+    ltp_scale: f32,
+    lpc: [f32; 16],
+    gains: [f32; 2],
+
+    rand_scale: f32,
+    seed: u32,
+    losses: usize,
+    // Energy of the last concealed frame
+    energy: f32,
+}
+
+// Inverse of the prediction gain of the lpc filter, 0 if unstable
+fn inverse_pred_gain(lpc: &[f32]) -> f32 {
+    let mut a = [0f32; 16];
+    let mut inv_gain = 1f32;
+
+    a[..lpc.len()].copy_from_slice(lpc);
+
+    for k in (0..lpc.len()).rev() {
+        let rc = -a[k];
+
+        if rc.abs() > 0.99997 {
+            return 0.0;
+        }
+
+        let div = 1.0 - rc * rc;
+        inv_gain *= div;
+
+        for n in 0..(k + 1) / 2 {
+            let (a0, a1) = (a[n], a[k - n - 1]);
+            a[n] = (a0 - a1 * rc) / div;
+            a[k - n - 1] = (a1 - a0 * rc) / div;
+        }
+    }
+
+    inv_gain
+}
+
+#[derive(Debug, Default, Clone)]
 pub struct SilkFrame {
     frame_type: FrameType,
     log_gain: isize,
@@ -1311,6 +1370,10 @@ pub struct SilkFrame {
     */
     output: Vec<f32>,
     lpc_history: Vec<f32>,
+
+    // Excitation of the last decoded frame, the concealment noise source
+    excitation: Vec<f32>,
+    plc: Plc,
 }
 
 impl SilkFrame {
@@ -1319,6 +1382,7 @@ impl SilkFrame {
 
         f.output.resize(2 * LPC_HISTORY, 0f32);
         f.lpc_history.resize(2 * LPC_HISTORY, 0f32);
+        f.excitation.resize(MAX_FRAME_SIZE, 0f32);
 
         f
     }
@@ -1658,7 +1722,199 @@ impl SilkFrame {
 
             self.output.resize(2 * LPC_HISTORY, 0f32);
             self.lpc_history.resize(2 * LPC_HISTORY, 0f32);
+
+            self.excitation = vec![0f32; MAX_FRAME_SIZE];
+            self.plc = Plc::default();
+        }
+    }
+
+    // Keep the parameters of the frame just decoded for the concealment
+    fn update_plc(&mut self, info: &SilkInfo, sfs: &[SubFrame], ltp_scale: f32) {
+        let plc = &mut self.plc;
+        let last = &sfs[sfs.len() - 1];
+
+        if self.frame_type.voiced {
+            // Use the strongest pitch pulse within a pitch period from the end
+            let mut ltp_gain = 0.0;
+            for (_, sf) in sfs
+                .iter()
+                .rev()
+                .enumerate()
+                .take_while(|(j, _)| j * info.sf_size < last.pitch_lag as usize)
+            {
+                let gain = sf.ltp_taps.iter().sum();
+                if gain > ltp_gain {
+                    ltp_gain = gain;
+                    plc.ltp_taps = sf.ltp_taps;
+                    plc.pitch_lag = sf.pitch_lag as f32;
+                }
+            }
+
+            if ltp_gain > 0.0 {
+                let scale = ltp_gain.max(PLC_LTP_GAIN_MIN).min(PLC_LTP_GAIN_MAX) / ltp_gain;
+                plc.ltp_taps.iter_mut().for_each(|t| *t *= scale);
+            } else {
+                plc.ltp_taps = [0.0; LTP_ORDER];
+            }
+        } else {
+            plc.pitch_lag = (PLC_MAX_PITCH_LAG_MS * info.sf_size / 5) as f32;
+            plc.ltp_taps = [0.0; LTP_ORDER];
+        }
+
+        plc.lpc = self.lpc;
+        plc.ltp_scale = ltp_scale;
+        plc.gains = [sfs[sfs.len() - 2].gain, last.gain];
+    }
+
+    // Fade in the first frame after a loss if louder than the concealed one
+    fn glue_plc(&mut self, len: usize) {
+        if self.plc.losses == 0 {
+            return;
+        }
+
+        let out = &mut self.output[LPC_HISTORY - len..LPC_HISTORY];
+        let energy: f32 = out.iter().map(|v| v * v).sum();
+
+        if energy > self.plc.energy {
+            let mut gain = (self.plc.energy / energy).sqrt();
+            // 4 times steeper than a fade over the whole frame
+            let slope = 4.0 * (1.0 - gain) / len as f32;
+
+            for o in out.iter_mut() {
+                *o *= gain;
+                gain += slope;
+                if gain > 1.0 {
+                    break;
+                }
+            }
+        }
+
+        self.plc.losses = 0;
+    }
+
+    // Extrapolate a lost frame from the last decoded one
+    fn conceal(&mut self, info: &SilkInfo) {
+        let f_size = info.f_size;
+        let sf_size = info.sf_size;
+        let subframes = info.subframes;
+        let order = if info.bandwidth > Bandwidth::Medium {
+            WB::ORDER
+        } else {
+            NB_MB::ORDER
+        };
+        let max_lag = (PLC_MAX_PITCH_LAG_MS * sf_size / 5) as f32;
+
+        // Nothing to extrapolate from, the history is silent
+        if !self.coded {
+            return;
+        }
+
+        let excitation = &self.excitation;
+        let plc = &mut self.plc;
+        let loss = plc.losses.min(1);
+        let voiced = self.frame_type.voiced;
+
+        // The noise comes from the last two subframes, the lower energy one
+        let energy = |i: usize, gain: f32| -> f32 {
+            excitation[i * sf_size..(i + 1) * sf_size]
+                .iter()
+                .map(|e| (e * gain) * (e * gain))
+                .sum()
+        };
+        let end = if energy(subframes - 2, plc.gains[0]) < energy(subframes - 1, plc.gains[1]) {
+            (subframes - 1) * sf_size
+        } else {
+            subframes * sf_size
+        };
+        let noise = &excitation[end.saturating_sub(PLC_RAND_BUF_SIZE)..][..PLC_RAND_BUF_SIZE];
+
+        let harm_att = PLC_HARM_ATT[loss];
+        let mut rand_att = if voiced {
+            PLC_RAND_ATT_VOICED[loss]
+        } else {
+            PLC_RAND_ATT_UNVOICED[loss]
+        };
+
+        // Bandwidth expansion, stronger on every loss
+        let mut chirp = PLC_BWE;
+        for c in plc.lpc[..order].iter_mut() {
+            *c *= chirp;
+            chirp *= PLC_BWE;
+        }
+        let lpc = &plc.lpc[..order];
+
+        if plc.losses == 0 {
+            plc.rand_scale = 1.0;
+            if voiced {
+                let ltp_gain: f32 = plc.ltp_taps.iter().sum();
+                plc.rand_scale = (1.0 - ltp_gain).max(0.2) * plc.ltp_scale;
+            } else {
+                // Less noise for the filters with a high prediction gain
+                let inv_gain = inverse_pred_gain(lpc).max(1.0 / 256.0).min(1.0 / 8.0);
+                rand_att *= inv_gain * 8.0;
+            }
+        }
+
+        // Re-whiten the past output to get the LTP excitation
+        let mut lag = plc.pitch_lag.round() as usize;
+        let mut exc = [0f32; LPC_HISTORY + MAX_FRAME_SIZE];
+        let start = LPC_HISTORY - lag - LTP_ORDER / 2;
+
+        for i in start..LPC_HISTORY {
+            let mut sum = self.output[i];
+            for (k, &c) in lpc.iter().enumerate() {
+                sum -= c * self.output[i - k - 1];
+            }
+            exc[i] = sum / plc.gains[1];
+        }
+
+        // Pitch periodic extrapolation, fading to noise
+        let mut seed = plc.seed;
+        for k in 0..subframes {
+            let start = LPC_HISTORY + k * sf_size;
+
+            for i in start..start + sf_size {
+                seed = seed.wrapping_mul(196314165).wrapping_add(907633515);
+                let r = noise[(seed >> 25) as usize & (PLC_RAND_BUF_SIZE - 1)];
+
+                // 5-tap filter centered on the pitch lag
+                let ltp: f32 = plc
+                    .ltp_taps
+                    .iter()
+                    .enumerate()
+                    .map(|(j, &t)| t * exc[i + LTP_ORDER / 2 - lag - j])
+                    .sum();
+
+                exc[i] = ltp + r * plc.rand_scale;
+            }
+
+            plc.ltp_taps.iter_mut().for_each(|t| *t *= harm_att);
+            if self.frame_type.active {
+                plc.rand_scale *= rand_att;
+            }
+
+            plc.pitch_lag = (plc.pitch_lag * (1.0 + PLC_PITCH_DRIFT)).min(max_lag);
+            lag = plc.pitch_lag.round() as usize;
         }
+
+        for i in LPC_HISTORY..LPC_HISTORY + f_size {
+            let mut sum = exc[i] * plc.gains[1];
+            for (k, &c) in lpc.iter().enumerate() {
+                sum += c * self.lpc_history[i - k - 1];
+            }
+            self.lpc_history[i] = sum;
+            self.output[i] = sum.max(-1f32).min(1f32);
+        }
+
+        plc.energy = self.output[LPC_HISTORY..LPC_HISTORY + f_size]
+            .iter()
+            .map(|v| v * v)
+            .sum();
+        plc.seed = seed;
+        plc.losses += 1;
+
+        self.lpc_history.copy_within(f_size..f_size + LPC_HISTORY, 0);
+        self.output.copy_within(f_size..f_size + LPC_HISTORY, 0);
     }
 
     fn parse(
@@ -1769,6 +2025,9 @@ impl SilkFrame {
             }
         }
 
+        self.excitation[..info.f_size]
+            .copy_from_slice(&residuals[RES_HISTORY..RES_HISTORY + info.f_size]);
+
         // println!("residuals {:?}", &residuals);
 
         // if self.mono_only { return Ok(()) }
@@ -1897,6 +2156,9 @@ impl SilkFrame {
             ); */
         }
 
+        self.update_plc(info, &sfs[..info.subframes], ltpscale);
+        self.glue_plc(info.f_size);
+
         self.coded = true;
 
         Ok(())
@@ -1928,6 +2190,11 @@ impl Silk {
             side_frame: SilkFrame::new(),
             left_outbuf: vec![0f32; 960],
             right_outbuf: vec![0f32; 960],
+
+            resampler: [
+                Resampler::new(Bandwidth::Wide),
+                Resampler::new(Bandwidth::Wide),
+            ],
         }
     }
 
@@ -1937,6 +2204,10 @@ impl Silk {
 
         self.info.prev0 = 0.0;
         self.info.prev1 = 0.0;
+
+        for r in self.resampler.iter_mut() {
+            r.reset();
+        }
     }
 
     pub fn setup(&mut self, pkt: &Packet) {
@@ -1959,8 +2230,18 @@ impl Silk {
             }
             _ => unreachable!(),
         }
+        let bandwidth = pkt.bandwidth.min(Bandwidth::Wide);
+
+        // The resampler state is valid only for the same internal rate
+        if bandwidth != self.info.bandwidth {
+            self.resampler = [Resampler::new(bandwidth), Resampler::new(bandwidth)];
+        } else if pkt.stereo && !self.stereo {
+            // The second channel picks up from the mono output
+            self.resampler[1] = self.resampler[0].clone();
+        }
+
         self.stereo = pkt.stereo;
-        self.info.bandwidth = pkt.bandwidth.min(Bandwidth::Wide);
+        self.info.bandwidth = bandwidth;
         self.info.sf_size = match self.info.bandwidth {
             Bandwidth::Narrow => 40,
             Bandwidth::Medium => 60,
@@ -2060,57 +2341,136 @@ impl Silk {
         self.info.prev1 = self.info.weight1;
     }
 
+    // The LBRR frames are decoded on a copy of the channel state,
+    // the regular frames following them must not be affected.
+    fn skip_lbrr(
+        &mut self,
+        rd: &mut RangeDecoder,
+        mid_lbrr: &[bool],
+        side_lbrr: &[bool],
+    ) -> Result<()> {
+        let mut mid_frame = self.mid_frame.clone();
+        let mut side_frame = self.side_frame.clone();
+
+        for i in 0..self.frames {
+            // Conditional coding only if the previous frame has LBRR data as well
+            if mid_lbrr[i] {
+                let first = i == 0 || !mid_lbrr[i - 1];
+                if self.stereo {
+                    self.parse_stereo_weight(rd, side_lbrr[i]);
+                }
+                mid_frame.parse(rd, &self.info, true, first)?;
+            }
+
+            if side_lbrr[i] {
+                let first = i == 0 || !side_lbrr[i - 1];
+                side_frame.parse(rd, &self.info, true, first)?;
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn decode(&mut self, rd: &mut RangeDecoder) -> Result<usize> {
+        self.decode_frames(rd, false)
+    }
+
+    /// Decode the LBRR frames in place of the regular ones
+    ///
+    /// They carry a lower quality copy of the frames of the previous packet,
+    /// use it to recover it when lost.
+    pub fn decode_fec(&mut self, rd: &mut RangeDecoder) -> Result<usize> {
+        self.decode_frames(rd, true)
+    }
+
+    fn decode_frames(&mut self, rd: &mut RangeDecoder, fec: bool) -> Result<usize> {
         let mut mid_vad = [false; 3];
         let mut side_vad = [false; 3];
-        fn lp(rd: &mut RangeDecoder, vad: &mut [bool]) -> Result<()> {
+        let mut mid_lbrr = [false; 3];
+        let mut side_lbrr = [false; 3];
+
+        fn lp(rd: &mut RangeDecoder, vad: &mut [bool]) -> bool {
             for v in vad {
                 *v = rd.decode_logp(1);
             }
-            if rd.decode_logp(1) {
-                return Err(Error::Unsupported("LBRR frames".to_owned()));
+            rd.decode_logp(1)
+        }
+
+        fn lbrr_flags(rd: &mut RangeDecoder, lbrr: &mut [bool]) {
+            if lbrr.len() == 1 {
+                lbrr[0] = true;
             } else {
-                Ok(())
+                let mask = rd.decode_icdf(LBRR_FLAGS[lbrr.len() - 2]) + 1;
+                for (i, l) in lbrr.iter_mut().enumerate() {
+                    *l = (mask >> i) & 1 != 0;
+                }
             }
         }
 
-        lp(rd, &mut mid_vad[..self.frames])?;
+        let mid_coded = lp(rd, &mut mid_vad[..self.frames]);
+        let side_coded = self.stereo && lp(rd, &mut side_vad[..self.frames]);
 
-        if self.stereo {
-            lp(rd, &mut side_vad[..self.frames])?;
+        if mid_coded {
+            lbrr_flags(rd, &mut mid_lbrr[..self.frames]);
+        }
+        if side_coded {
+            lbrr_flags(rd, &mut side_lbrr[..self.frames]);
+        }
+
+        if !fec && (mid_coded || side_coded) {
+            self.skip_lbrr(rd, &mid_lbrr, &side_lbrr)?;
         }
+
         //        println!("{:?} {:?}", mid_vad, side_vad);
         for i in 0..self.frames {
-            let first = i == 0;
+            let out_range = i * self.info.f_size..(i + 1) * self.info.f_size;
+
+            // Without LBRR data for the mid channel the stereo weights are
+            // kept from the previous frame and the mid channel is concealed,
+            // the side channel may still have its own LBRR frame.
+            if fec && !mid_lbrr[i] {
+                self.mid_frame.conceal(&self.info);
+                if self.stereo && side_lbrr[i] {
+                    let side_first = i == 0 || !side_lbrr[i - 1];
+                    self.side_frame.parse(rd, &self.info, true, side_first)?;
+                } else if self.stereo {
+                    self.side_frame.conceal(&self.info);
+                }
+
+                self.output(out_range);
+                continue;
+            }
+
+            // The LBRR frames are always coded as active and use conditional
+            // coding only if the previous frame has LBRR data as well.
+            let (mid_vad, side_vad, mid_first, side_first) = if fec {
+                (
+                    true,
+                    side_lbrr[i],
+                    i == 0 || !mid_lbrr[i - 1],
+                    i == 0 || !side_lbrr[i - 1],
+                )
+            } else {
+                (mid_vad[i], side_vad[i], i == 0, i == 0)
+            };
+
             let midonly = if self.stereo {
-                self.parse_stereo_weight(rd, side_vad[i])
+                self.parse_stereo_weight(rd, side_vad)
             } else {
                 false
             };
             //            println!("{} midonly {} stereo {}", i, midonly, self.stereo);
-            self.mid_frame.parse(rd, &self.info, mid_vad[i], first)?;
+            self.mid_frame.parse(rd, &self.info, mid_vad, mid_first)?;
 
-            if self.stereo && !midonly {
-                self.side_frame.parse(rd, &self.info, side_vad[i], first)?;
-            }
+            let side = if fec { side_lbrr[i] } else { !midonly };
 
-            if midonly {
+            if self.stereo && side {
+                self.side_frame.parse(rd, &self.info, side_vad, side_first)?;
+            } else {
                 self.side_frame.flush();
             }
-            let out_range = i * self.info.f_size..(i + 1) * self.info.f_size;
-            if self.stereo && self.stereo_out {
-                // println!("unmix");
-                self.unmix_ms(out_range);
-            } else {
-                let in_start = LPC_HISTORY - self.info.f_size - 2;
-                let in_range = in_start..in_start + self.info.f_size;
-                let inbuf = &self.mid_frame.output[in_range];
 
-                if self.stereo_out {
-                    self.left_outbuf[out_range.clone()].copy_from_slice(inbuf);
-                }
-                self.right_outbuf[out_range].copy_from_slice(inbuf);
-            }
+            self.output(out_range);
         }
 
         /*        println!("stereo {} out {}", self.stereo, self.stereo_out);
@@ -2125,4 +2485,59 @@ impl Silk {
         */
         Ok(0)
     }
+
+    /// Conceal a single lost frame of 10 or 20ms
+    pub fn conceal(&mut self, frame_duration: FrameDuration) {
+        self.frames = 1;
+        self.info.subframes = if frame_duration == FrameDuration::Medium {
+            2
+        } else {
+            4
+        };
+        self.info.f_size = self.info.sf_size * self.info.subframes;
+
+        self.left_outbuf.resize(self.info.f_size, 0f32);
+        self.right_outbuf.resize(self.info.f_size, 0f32);
+
+        self.mid_frame.conceal(&self.info);
+        if self.stereo {
+            self.side_frame.conceal(&self.info);
+        }
+
+        self.output(0..self.info.f_size);
+    }
+
+    // Write the last frame to the output buffers
+    fn output(&mut self, out_range: Range<usize>) {
+        if self.stereo && self.stereo_out {
+            // println!("unmix");
+            self.unmix_ms(out_range);
+        } else {
+            let in_start = LPC_HISTORY - self.info.f_size - 1;
+            let in_range = in_start..in_start + self.info.f_size;
+            let inbuf = &self.mid_frame.output[in_range];
+
+            if self.stereo_out {
+                self.left_outbuf[out_range.clone()].copy_from_slice(inbuf);
+            }
+            self.right_outbuf[out_range].copy_from_slice(inbuf);
+        }
+    }
+
+    /// Upsample the decoded output to 48kHz, `out` holds one run per output channel
+    pub fn resample(&mut self, out: &mut [f32]) {
+        let len = self.frames * self.info.f_size;
+        let size = out.len() / (self.stereo_out as usize + 1);
+        let (left, right) = out.split_at_mut(size);
+
+        self.resampler[0].process(left, &self.right_outbuf[..len]);
+
+        if self.stereo_out {
+            if self.stereo {
+                self.resampler[1].process(right, &self.left_outbuf[..len]);
+            } else {
+                right.copy_from_slice(left);
+            }
+        }
+    }
 }
@@ -1,4 +1,5 @@
 mod decoder;
+mod resampler;
 mod tables;
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,194 @@
+//!
+//! Silk output resampling
+//!
+//! Float port of the libopus decoder upsampler: a 2x all-pass upsampler
+//! followed by a 12-phase fractional FIR interpolator.
+//!
+
+use crate::packet::Bandwidth;
+
+const ORDER_FIR: usize = 8;
+const MAX_BATCH_SIZE_MS: usize = 10;
+const MAX_FS_KHZ: usize = 16;
+const OUT_FS_KHZ: usize = 48;
+
+// All-pass coefficients of the even and odd output branches (Q16 in libopus)
+const UP2_HQ_0: [f32; 3] = [1746.0 / 65536.0, 14986.0 / 65536.0, 39083.0 / 65536.0];
+const UP2_HQ_1: [f32; 3] = [6854.0 / 65536.0, 25769.0 / 65536.0, 55542.0 / 65536.0];
+
+const FRAC_FIR_12: [[f32; ORDER_FIR / 2]; 12] = [
+    [189.0, -600.0, 617.0, 30567.0],
+    [117.0, -159.0, -1070.0, 29704.0],
+    [52.0, 221.0, -2392.0, 28276.0],
+    [-4.0, 529.0, -3350.0, 26341.0],
+    [-48.0, 758.0, -3956.0, 23973.0],
+    [-80.0, 905.0, -4235.0, 21254.0],
+    [-99.0, 972.0, -4222.0, 18278.0],
+    [-107.0, 967.0, -3957.0, 15143.0],
+    [-103.0, 896.0, -3487.0, 11950.0],
+    [-91.0, 773.0, -2865.0, 8798.0],
+    [-71.0, 611.0, -2143.0, 5784.0],
+    [-46.0, 425.0, -1375.0, 2996.0],
+];
+
+#[derive(Debug, Clone)]
+pub struct Resampler {
+    fs_in: usize, // kHz
+    input_delay: usize,
+    inv_ratio: i32, // Q16
+
+    iir: [f32; 6],
+    fir: [f32; ORDER_FIR],
+    delay: [f32; MAX_FS_KHZ],
+}
+
+impl Resampler {
+    /// Upsampler from the SILK internal rate of `bandwidth` to 48kHz
+    pub fn new(bandwidth: Bandwidth) -> Self {
+        // Same input delays as libopus, they align the SILK output with the CELT one
+        let (fs_in, input_delay) = match bandwidth {
+            Bandwidth::Narrow => (8, 0),
+            Bandwidth::Medium => (12, 4),
+            Bandwidth::Wide => (16, 7),
+            _ => unreachable!(),
+        };
+
+        Resampler {
+            fs_in,
+            input_delay,
+            inv_ratio: inv_ratio(fs_in),
+
+            iir: [0f32; 6],
+            fir: [0f32; ORDER_FIR],
+            delay: [0f32; MAX_FS_KHZ],
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.iir = [0f32; 6];
+        self.fir = [0f32; ORDER_FIR];
+        self.delay = [0f32; MAX_FS_KHZ];
+    }
+
+    // Upsample by 2 with two third order all-pass chains
+    fn up2(&mut self, out: &mut [f32], inp: &[f32]) {
+        fn allpass(s: &mut [f32], coeffs: &[f32; 3], v: f32) -> f32 {
+            coeffs.iter().zip(s.iter_mut()).fold(v, |v, (&c, s)| {
+                let x = (v - *s) * c;
+                let out = *s + x;
+                *s = v + x;
+                out
+            })
+        }
+
+        let (s0, s1) = self.iir.split_at_mut(3);
+
+        for (o, &v) in out.chunks_exact_mut(2).zip(inp.iter()) {
+            o[0] = allpass(s0, &UP2_HQ_0, v);
+            o[1] = allpass(s1, &UP2_HQ_1, v);
+        }
+    }
+
+    fn iir_fir(&mut self, out: &mut [f32], mut inp: &[f32]) -> usize {
+        let mut buf = [0f32; 2 * MAX_BATCH_SIZE_MS * MAX_FS_KHZ + ORDER_FIR];
+        let batch = self.fs_in * MAX_BATCH_SIZE_MS;
+        let mut written = 0;
+
+        buf[..ORDER_FIR].copy_from_slice(&self.fir);
+
+        loop {
+            let len = inp.len().min(batch);
+
+            self.up2(&mut buf[ORDER_FIR..ORDER_FIR + 2 * len], &inp[..len]);
+
+            // Interpolate the 2x upsampled signal
+            let max_index = (len as i32) << 17;
+            for index in (0..max_index).step_by(self.inv_ratio as usize) {
+                let phase = (((index & 0xffff) * 12) >> 16) as usize;
+                let b = &buf[(index >> 16) as usize..][..ORDER_FIR];
+                let c0 = &FRAC_FIR_12[phase];
+                let c1 = &FRAC_FIR_12[11 - phase];
+
+                let sum = b[0] * c0[0]
+                    + b[1] * c0[1]
+                    + b[2] * c0[2]
+                    + b[3] * c0[3]
+                    + b[4] * c1[3]
+                    + b[5] * c1[2]
+                    + b[6] * c1[1]
+                    + b[7] * c1[0];
+
+                out[written] = sum / 32768.0;
+                written += 1;
+            }
+
+            inp = &inp[len..];
+
+            if inp.is_empty() {
+                self.fir.copy_from_slice(&buf[2 * len..2 * len + ORDER_FIR]);
+                return written;
+            }
+
+            buf.copy_within(2 * len..2 * len + ORDER_FIR, 0);
+        }
+    }
+
+    /// Resample `inp` to 48kHz, `out` must hold `inp.len() * 48 / fs_in` samples
+    ///
+    /// The input is delayed so the output lines up with the CELT layer.
+    pub fn process(&mut self, out: &mut [f32], inp: &[f32]) {
+        let fs_in = self.fs_in;
+        let delay = self.input_delay;
+        let len = fs_in - delay;
+
+        self.delay[delay..fs_in].copy_from_slice(&inp[..len]);
+
+        let buffered = self.delay;
+        self.iir_fir(&mut out[..OUT_FS_KHZ], &buffered[..fs_in]);
+        self.iir_fir(&mut out[OUT_FS_KHZ..], &inp[len..inp.len() - delay]);
+
+        self.delay[..delay].copy_from_slice(&inp[inp.len() - delay..]);
+    }
+}
+
+// Input samples per output sample in Q16, rounded up
+fn inv_ratio(fs_in: usize) -> i32 {
+    let fs_in = fs_in as i64 * 1000;
+    let fs_out = OUT_FS_KHZ as i64 * 1000;
+    let mut ratio = ((fs_in << 15) / fs_out) << 2;
+
+    while (ratio * fs_out) >> 16 < fs_in << 1 {
+        ratio += 1;
+    }
+
+    ratio as i32
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn upsample_wideband() {
+        let mut r = Resampler::new(Bandwidth::Wide);
+        let inp: Vec<f32> = (0..320)
+            .map(|i| (i as f32 * 2.0 * std::f32::consts::PI * 1000.0 / 16000.0).sin() * 0.5)
+            .collect();
+        let mut out = vec![0f32; 960];
+
+        assert_eq!(r.inv_ratio, 43691);
+        assert_eq!(Resampler::new(Bandwidth::Medium).inv_ratio, 32768);
+        assert_eq!(Resampler::new(Bandwidth::Narrow).inv_ratio, 21846);
+
+        r.process(&mut out, &inp);
+
+        // Past the filter transient the output is the same tone at 48kHz,
+        // delayed by the input delay plus the filters group delay
+        let delay = 7 * 3 + 13;
+        for (i, &o) in out.iter().enumerate().skip(100) {
+            let t = (i - delay) as f32 / 48000.0;
+            let expected = (t * 2.0 * std::f32::consts::PI * 1000.0).sin() * 0.5;
+            assert!((o - expected).abs() < 0.01, "{} {} {}", i, o, expected);
+        }
+    }
+}
@@ -23,6 +23,18 @@ pub const MID_ONLY: &ICDFContext = &ICDFContext {
     dist: &[192, 256],
 };
 
+// Per-frame LBRR flags of 40ms and 60ms packets, the decoded value + 1 is the mask
+pub const LBRR_FLAGS: &[&ICDFContext; 2] = &[
+    &ICDFContext {
+        total: 256,
+        dist: &[53, 106, 256],
+    },
+    &ICDFContext {
+        total: 256,
+        dist: &[41, 61, 90, 131, 146, 174, 256],
+    },
+];
+
 pub const FRAME_TYPE_INACTIVE: &ICDFContext = &ICDFContext {
     total: 256,
     dist: &[26, 256],
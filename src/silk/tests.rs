@@ -28,7 +28,6 @@ fn decode_midonly_to_stereo() {
     ];
 
     let outbuf = vec![
-        0.0,
         0.0,
         0.000018067658,
         0.000040303756,
@@ -508,6 +507,7 @@ fn decode_midonly_to_stereo() {
         -0.00027067948,
         -0.00030915916,
         -0.00029481357,
+        -0.0002873543,
     ];
 
     decode(in_slice, true, &outbuf, &outbuf);
@@ -868,3 +868,24 @@ fn lsf_to_lpc() {
 
     assert_eq!(lpc, reference);
 }
+
+#[test]
+// 40ms stereo narrowband packet, with LBRR data only for the side channel
+fn decode_fec_side_only() {
+    let in_slice = &[
+        20, 29, 198, 125, 199, 185, 46, 251, 29, 109, 28, 138, 29, 104, 12, 162, 212, 29, 62, 93,
+        34, 227, 136, 31, 107, 17, 211, 70, 46, 145, 230, 50, 248, 144, 122, 242, 163, 65, 253, 20,
+        172, 61, 127, 116, 153, 110, 130, 47, 127, 49, 155, 64,
+    ];
+    let p = Packet::from_slice(in_slice).unwrap();
+
+    let mut silk = Silk::new(true);
+    silk.setup(&p);
+
+    let mut rd = RangeDecoder::new(p.frames[0]);
+    silk.decode_fec(&mut rd).unwrap();
+
+    // Both side LBRR frames are parsed, the final range matches libopus
+    assert_eq!(rd.range, 1477590022);
+    assert!(silk.left_outbuf.iter().all(|v| v.is_finite()));
+}